@@ -0,0 +1,251 @@
+use std::{
+    error::Error,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use common::{
+    codec::{Codec, LineCodec},
+    command::Command,
+    connection::{Connection, ConnectionReader, ConnectionWriter},
+    event::ServerEvent,
+    protocol,
+};
+use futures_util::Stream;
+use log::debug;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, ToSocketAddrs},
+    sync::mpsc,
+    task::JoinHandle,
+};
+
+use crate::render_event;
+
+pub type ClientResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/**
+ * How many commands or events may wait on the connection before `send` (or the connection) waits.
+ */
+const QUEUE_CAPACITY: usize = 1024;
+
+/**
+ * A joined chat session. Commands go out through `send`, `command` and `leave`; everything the server
+ * sends arrives through the `Stream` impl, starting with its hello and welcome. The stream ends when
+ * the server closes the connection.
+ */
+pub struct ChatClient {
+    username: String,
+    commands: mpsc::Sender<Command>,
+    events: mpsc::Receiver<ServerEvent>,
+    writer: JoinHandle<()>,
+}
+
+impl ChatClient {
+    /**
+     * Connects to the server at `address` and joins as `username`, failing if the name is turned down.
+     */
+    pub async fn connect(address: impl ToSocketAddrs, username: &str) -> ClientResult<Self> {
+        Self::connect_with_codec(address, username, LineCodec::default()).await
+    }
+
+    pub async fn connect_with_codec<C>(
+        address: impl ToSocketAddrs,
+        username: &str,
+        codec: C,
+    ) -> ClientResult<Self>
+    where
+        C: Codec + Clone + Send + 'static,
+        C::Item: Send,
+    {
+        let stream = TcpStream::connect(address).await?;
+        Self::start(
+            Connection::new(stream, codec),
+            username,
+            async |_: &ServerEvent| None,
+        )
+        .await
+    }
+
+    /**
+     * Says hello and joins as `username` over an open connection. If the server turns the name down,
+     * `rename` is shown why and asked for another; returning `None` gives up.
+     */
+    pub async fn start<S, C>(
+        mut connection: Connection<S, C>,
+        username: &str,
+        rename: impl AsyncFnMut(&ServerEvent) -> Option<String>,
+    ) -> ClientResult<Self>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        C: Codec + Clone + Send + 'static,
+        C::Item: Send,
+    {
+        let hello = handshake(&mut connection).await?;
+        let (username, welcome) = join(&mut connection, username.to_string(), rename).await?;
+
+        let (commands, outgoing) = mpsc::channel(QUEUE_CAPACITY);
+        let (incoming, events) = mpsc::channel(QUEUE_CAPACITY);
+        incoming.try_send(hello)?;
+        incoming.try_send(welcome)?;
+        // Reading and writing run on their own tasks, so events show up while commands go out
+        let (reader, writer) = connection.split();
+        tokio::spawn(read_events(reader, incoming));
+        let writer = tokio::spawn(write_commands(writer, outgoing));
+        Ok(ChatClient {
+            username,
+            commands,
+            events,
+            writer,
+        })
+    }
+
+    /**
+     * Our name as the server knows it: the one it welcomed us with, then whatever we have been renamed
+     * to, as of the last event taken from the stream.
+     */
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /**
+     * Says `text` to our current room.
+     */
+    pub async fn send(&self, text: impl Into<String>) -> ClientResult<()> {
+        self.command(Command::SendMessage(text.into())).await
+    }
+
+    /**
+     * Queues any command for the server; replies arrive on the stream.
+     */
+    pub async fn command(&self, command: Command) -> ClientResult<()> {
+        self.commands
+            .send(command)
+            .await
+            .map_err(|_| "Connection closed".into())
+    }
+
+    /**
+     * Leaves the chat, returning once the server has been told and the connection is closed.
+     */
+    pub async fn leave(self) -> ClientResult<()> {
+        let ChatClient {
+            commands,
+            events,
+            writer,
+            ..
+        } = self;
+        drop(events);
+        commands.send(Command::Leave).await?;
+        // With the last sender gone the writer stops once the leave is written
+        drop(commands);
+        writer.await?;
+        Ok(())
+    }
+}
+
+impl Stream for ChatClient {
+    type Item = ServerEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ServerEvent>> {
+        let polled = self.events.poll_recv(cx);
+        if let Poll::Ready(Some(ServerEvent::UserRenamed { from, to })) = &polled {
+            if *from == self.username {
+                self.username = to.clone();
+            }
+        }
+        polled
+    }
+}
+
+/**
+ * Writes queued commands until the queue closes or the connection fails.
+ */
+async fn write_commands<S, C>(
+    mut writer: ConnectionWriter<S, C>,
+    mut outgoing: mpsc::Receiver<Command>,
+) where
+    S: AsyncWrite + Unpin,
+    C: Codec,
+{
+    while let Some(command) = outgoing.recv().await {
+        debug!("Sent command : {:?}", command.to_string());
+        if let Err(e) = writer.send_command(command).await {
+            debug!("Failed to send command: {}", e);
+            return;
+        }
+    }
+    let _ = writer.close().await;
+}
+
+/**
+ * Passes events on as they arrive, until the server closes the connection.
+ */
+async fn read_events<S, C>(mut reader: ConnectionReader<S, C>, incoming: mpsc::Sender<ServerEvent>)
+where
+    S: AsyncRead + Unpin,
+    C: Codec,
+{
+    loop {
+        match reader.read_event().await {
+            // Nobody listening (say, while leaving) is no reason to stop reading
+            Ok(Some(event)) => {
+                let _ = incoming.send(event).await;
+            }
+            Ok(None) => return,
+            // Say, an event from a newer server; the next one may still make sense
+            Err(e) if e.is_bad_message() => debug!("Skipped event from server: {}", e),
+            Err(e) => {
+                debug!("Failed to read event from server: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/**
+ * Announces our protocol version to the server and waits for it to accept us, returning its reply.
+ */
+async fn handshake<S, C>(connection: &mut Connection<S, C>) -> ClientResult<ServerEvent>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+{
+    connection.send_command(protocol::hello()).await?;
+    match connection.read_event().await? {
+        Some(event @ ServerEvent::HelloAck { .. }) => Ok(event),
+        Some(event) => Err(render_event(&event).into()),
+        None => Err("Connection closed by server during handshake".into()),
+    }
+}
+
+/**
+ * Joins the chat as `username`, asking `rename` for another name for as long as the server turns it
+ * down. Returns the name the server welcomed us with, and the welcome.
+ */
+async fn join<S, C>(
+    connection: &mut Connection<S, C>,
+    mut username: String,
+    mut rename: impl AsyncFnMut(&ServerEvent) -> Option<String>,
+) -> ClientResult<(String, ServerEvent)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+{
+    loop {
+        connection
+            .send_command(Command::Join(username.clone()))
+            .await?;
+        match connection.read_event().await? {
+            // The server may have tidied the name up, so go by what it says
+            Some(ServerEvent::Welcome(username)) => {
+                return Ok((username.clone(), ServerEvent::Welcome(username)))
+            }
+            Some(event @ (ServerEvent::UsernameTaken | ServerEvent::InvalidUsername(_))) => {
+                username = rename(&event).await.ok_or_else(|| render_event(&event))?;
+            }
+            Some(event) => return Err(render_event(&event).into()),
+            None => return Err("Connection closed by server while joining".into()),
+        }
+    }
+}
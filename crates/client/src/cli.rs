@@ -1,44 +1,541 @@
-use clap::{arg, command, Parser};
-use common::command::Command;
-use futures_util::stream::StreamExt;
-use std::sync::Arc;
-use tokio::io::{self as tokio_io};
+use clap::Parser;
+use common::{
+    command::{parse_history, Command},
+    config::ConfigArgs,
+    event::ServerEvent,
+};
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    history::DefaultHistory, validate::Validator, Context, Editor, ExternalPrinter, Helper,
+};
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+use tokio::io::{self as tokio_io, AsyncBufReadExt, BufReader};
 use tokio::sync::mpsc::Sender;
-use tokio_util::codec::{FramedRead, LinesCodec};
 
-pub async fn run_cli(tx: Arc<Sender<Command>>) {
-    let stdin = tokio_io::stdin();
-    let mut reader = FramedRead::new(stdin, LinesCodec::new());
+const PROMPT: &str = "> ";
 
-    loop {
-        println!("\n\rEnter command (send <MSG>/leave): ");
+const GREETING: &str = "Type a message and press Enter to send it, or /help for commands.";
+
+/**
+ * Where typed lines are kept between runs, under the home directory.
+ */
+const HISTORY_FILE: &str = ".simple_chat_history";
+
+/**
+ * What a slash command asks for once its arguments have been read.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Send(Command),
+    Help,
+}
+
+/**
+ * One entry in a `CommandTable`. `run` gets everything after the name, trimmed, and returns `None`
+ * if that doesn't fit, in which case the usage is shown.
+ */
+#[derive(Clone)]
+pub struct SlashCommand {
+    pub name: &'static str,
+    pub args: &'static str,
+    pub about: &'static str,
+    pub run: fn(&str) -> Option<Outcome>,
+}
+
+/**
+ * What a typed line comes to: something for the server, or something to show the user.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    Send(Command),
+    Print(String),
+}
+
+/**
+ * Turns typed lines into commands, IRC style: a plain line is a message to the room and a line
+ * starting with `/` names a command from the table (`//` sends a message starting with `/`).
+ * `/help` is generated from whatever the table holds.
+ */
+#[derive(Clone)]
+pub struct CommandTable {
+    commands: Vec<SlashCommand>,
+}
+
+impl CommandTable {
+    pub fn new() -> Self {
+        CommandTable {
+            commands: Vec::new(),
+        }
+    }
+
+    /**
+     * Adds a command, replacing any that already has its name.
+     */
+    pub fn register(&mut self, command: SlashCommand) {
+        self.commands
+            .retain(|existing| existing.name != command.name);
+        self.commands.push(command);
+    }
+
+    pub fn parse(&self, line: &str) -> Input {
+        let Some(rest) = line.strip_prefix('/') else {
+            return Input::Send(Command::SendMessage(line.to_string()));
+        };
+        if rest.starts_with('/') {
+            return Input::Send(Command::SendMessage(rest.to_string()));
+        }
+        let (name, args) = rest.split_once(' ').unwrap_or((rest, ""));
+        let Some(command) = self.commands.iter().find(|command| command.name == name) else {
+            return Input::Print(format!("Unknown command /{}, try /help", name));
+        };
+        match (command.run)(args.trim()) {
+            Some(Outcome::Send(command)) => Input::Send(command),
+            Some(Outcome::Help) => Input::Print(self.help()),
+            None => Input::Print(format!("Usage: {}", usage(command))),
+        }
+    }
+
+    pub fn help(&self) -> String {
+        let lines: Vec<String> = self
+            .commands
+            .iter()
+            .map(|command| format!("  {:<24} {}", usage(command), command.about))
+            .collect();
+        format!(
+            "Type a message to send it to your room. Commands:\n{}",
+            lines.join("\n")
+        )
+    }
+
+    /**
+     * Every command as typed, e.g. `/who`, for completion.
+     */
+    pub fn names(&self) -> Vec<String> {
+        self.commands
+            .iter()
+            .map(|command| format!("/{}", command.name))
+            .collect()
+    }
+}
+
+impl Default for CommandTable {
+    /**
+     * The commands the client ships with.
+     */
+    fn default() -> Self {
+        let mut table = CommandTable::new();
+        for command in [
+            SlashCommand {
+                name: "join",
+                args: "<#ROOM>",
+                about: "Move into a room, creating it if needed",
+                run: |args| one_word(args).map(|room| Outcome::Send(Command::JoinRoom(room))),
+            },
+            SlashCommand {
+                name: "part",
+                args: "",
+                about: "Go back to #lobby",
+                run: |_| Some(Outcome::Send(Command::LeaveRoom)),
+            },
+            SlashCommand {
+                name: "rooms",
+                args: "",
+                about: "List the open rooms",
+                run: |_| Some(Outcome::Send(Command::ListRooms)),
+            },
+            SlashCommand {
+                name: "msg",
+                args: "<USER> <MSG>",
+                about: "Send a private message",
+                run: |args| {
+                    let (to, text) = args.split_once(' ')?;
+                    Some(Outcome::Send(Command::PrivateMessage {
+                        to: to.to_string(),
+                        text: text.trim().to_string(),
+                    }))
+                },
+            },
+            SlashCommand {
+                name: "who",
+                args: "",
+                about: "List who is online",
+                run: |_| Some(Outcome::Send(Command::ListUsers)),
+            },
+            SlashCommand {
+                name: "nick",
+                args: "<NAME>",
+                about: "Change your username",
+                run: |args| one_word(args).map(|name| Outcome::Send(Command::Nick(name))),
+            },
+            SlashCommand {
+                name: "history",
+                args: "<N> [SKIP]",
+                about: "Show the last N messages of your room, leaving out the newest SKIP",
+                run: |args| parse_history(args).map(Outcome::Send),
+            },
+            SlashCommand {
+                name: "leave",
+                args: "",
+                about: "Disconnect and exit",
+                run: |_| Some(Outcome::Send(Command::Leave)),
+            },
+            SlashCommand {
+                name: "help",
+                args: "",
+                about: "Show this list",
+                run: |_| Some(Outcome::Help),
+            },
+        ] {
+            table.register(command);
+        }
+        table
+    }
+}
+
+fn usage(command: &SlashCommand) -> String {
+    format!("/{} {}", command.name, command.args)
+        .trim_end()
+        .to_string()
+}
+
+fn one_word(args: &str) -> Option<String> {
+    (!args.is_empty() && !args.contains(' ')).then(|| args.to_string())
+}
+
+/**
+ * The line editor's side of the client: prints what the server sends without trampling what is
+ * being typed, and keeps the names tab completion offers up to date.
+ */
+pub struct Prompt {
+    printer: Option<Box<dyn ExternalPrinter + Send>>,
+    names: Arc<Mutex<Vec<String>>>,
+    /** `who` replies we asked for ourselves, which only feed completion. */
+    quiet_user_lists: usize,
+    stopping: Arc<AtomicBool>,
+}
+
+impl Prompt {
+    /**
+     * Starts reading lines on a thread of their own, turning each into a command through `commands` and
+     * sending it to `tx`. Ctrl-C or Ctrl-D sends `leave`. The thread drops `tx` once it has stopped and the terminal is back to normal,
+     * so wait for that before exiting.
+     */
+    pub fn start(commands: CommandTable, tx: Sender<Command>) -> rustyline::Result<Prompt> {
+        let names = Arc::new(Mutex::new(Vec::new()));
+        let mut editor = Editor::<ChatHelper, DefaultHistory>::new()?;
+        editor.set_helper(Some(ChatHelper {
+            commands: commands.names(),
+            names: names.clone(),
+        }));
+        let history = history_path();
+        if let Some(path) = &history {
+            // A missing file just means there is no history yet
+            let _ = editor.load_history(path);
+        }
+        // Not available when stdin or stdout isn't a terminal; plain printing is fine then
+        let printer = editor
+            .create_external_printer()
+            .ok()
+            .map(|printer| Box::new(printer) as Box<dyn ExternalPrinter + Send>);
+        println!("{}", GREETING);
+        let stopping = Arc::new(AtomicBool::new(false));
+        let stop = stopping.clone();
+        thread::spawn(move || read_lines(editor, history, &commands, tx, &stop));
+        Ok(Prompt {
+            printer,
+            names,
+            quiet_user_lists: 0,
+            stopping,
+        })
+    }
+
+    /**
+     * Prints a line above the prompt, redrawing whatever has been typed so far beneath it.
+     */
+    pub fn print(&mut self, line: String) {
+        match self.printer.as_mut() {
+            Some(printer) => {
+                if let Err(e) = printer.print(format!("{}\n", line)) {
+                    eprintln!("Error printing: {}", e);
+                }
+            }
+            None => println!("{}", line),
+        }
+    }
+
+    /**
+     * Asks the reading thread to stop after the line being typed now, since nothing will come of it.
+     */
+    pub fn stop(&mut self) {
+        self.stopping.store(true, Ordering::Relaxed);
+    }
 
-        let line = match reader.next().await.transpose() {
-            Ok(Some(line)) => line.trim().to_string(),
-            Ok(None) => {
-                eprintln!("No input received");
-                continue;
+    /**
+     * Counts on the next `who` reply being one we asked for to fill in completion, so it isn't printed.
+     */
+    pub fn expect_quiet_user_list(&mut self) {
+        self.quiet_user_lists += 1;
+    }
+
+    /**
+     * Notes who came and went for completion, then prints the event unless it was asked for quietly.
+     */
+    pub fn show(&mut self, event: &ServerEvent) {
+        {
+            let mut names = self.names.lock().unwrap();
+            match event {
+                ServerEvent::Welcome(name) | ServerEvent::UserJoined(name) => {
+                    names.push(name.clone())
+                }
+                ServerEvent::UserLeft { username, .. } => names.retain(|name| name != username),
+                ServerEvent::UserRenamed { from, to } => {
+                    names.retain(|name| name != from);
+                    names.push(to.clone());
+                }
+                ServerEvent::UserList(users) => {
+                    *names = users.iter().map(|user| user.username.clone()).collect();
+                }
+                _ => {}
+            }
+            names.sort();
+            names.dedup();
+        }
+        if matches!(event, ServerEvent::UserList(_)) && self.quiet_user_lists > 0 {
+            self.quiet_user_lists -= 1;
+            return;
+        }
+        self.print(crate::render_event(event));
+    }
+}
+
+/**
+ * Reads lines until the user leaves, we are asked to stop, or nobody is listening for commands any more.
+ * Only returns between lines, so the terminal is never left in raw mode.
+ */
+fn read_lines(
+    mut editor: Editor<ChatHelper, DefaultHistory>,
+    history: Option<PathBuf>,
+    commands: &CommandTable,
+    tx: Sender<Command>,
+    stopping: &AtomicBool,
+) {
+    loop {
+        let line = editor.readline(PROMPT);
+        if stopping.load(Ordering::Relaxed) {
+            return;
+        }
+        let line = match line {
+            Ok(line) => line.trim_end().to_string(),
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => {
+                let _ = tx.blocking_send(Command::Leave);
+                return;
             }
             Err(e) => {
                 eprintln!("Error reading input: {}", e);
-                continue;
+                let _ = tx.blocking_send(Command::Leave);
+                return;
             }
         };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line.as_str());
+        if let Some(path) = &history {
+            // Saved as we go, since the process may end while we're waiting on the next line
+            if let Err(e) = editor.save_history(path) {
+                eprintln!("Error saving history: {}", e);
+            }
+        }
 
-        match common::command::parse_command(&line) {
-            Some(command) => {
-                if let Err(e) = tx.send(command).await {
-                    eprintln!("Error sending command: {}", e);
+        match commands.parse(&line) {
+            Input::Send(command) => {
+                let leaving = matches!(command, Command::Leave);
+                if tx.blocking_send(command).is_err() || leaving {
+                    return;
                 }
             }
-            None => println!("\n\rInvalid command, please try again."),
+            Input::Print(text) => println!("{}", text),
         }
     }
 }
 
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+/**
+ * Tab completion: slash commands at the start of a line, online usernames anywhere else.
+ */
+struct ChatHelper {
+    commands: Vec<String>,
+    names: Arc<Mutex<Vec<String>>>,
+}
+
+impl Completer for ChatHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(complete(
+            line,
+            pos,
+            &self.commands,
+            &self.names.lock().unwrap(),
+        ))
+    }
+}
+
+/**
+ * Where the word under the cursor starts and what it could be completed to.
+ */
+fn complete(line: &str, pos: usize, commands: &[String], names: &[String]) -> (usize, Vec<String>) {
+    let start = line[..pos].rfind(' ').map_or(0, |space| space + 1);
+    let word = &line[start..pos];
+    let candidates = if start == 0 && word.starts_with('/') {
+        commands
+            .iter()
+            .filter(|command| command.starts_with(word))
+            .map(|command| format!("{} ", command))
+            .collect()
+    } else {
+        names
+            .iter()
+            .filter(|name| name.starts_with(word))
+            .cloned()
+            .collect()
+    };
+    (start, candidates)
+}
+
+impl Hinter for ChatHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ChatHelper {}
+
+impl Validator for ChatHelper {}
+
+impl Helper for ChatHelper {}
+
+/**
+ * Asks for a new username on stdin, returning `None` if stdin is closed or the answer is blank.
+ */
+pub async fn prompt_username() -> Option<String> {
+    println!("\n\rPlease choose another username: ");
+    let mut line = String::new();
+    BufReader::new(tokio_io::stdin())
+        .read_line(&mut line)
+        .await
+        .ok()?;
+    let username = line.trim();
+    (!username.is_empty()).then(|| username.to_string())
+}
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
     #[arg(short, long, default_value = "anon")]
     pub username: String,
+    /** Use the full-screen interface instead of the line prompt. */
+    #[arg(long)]
+    pub tui: bool,
+    #[command(flatten)]
+    pub config: ConfigArgs,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_completes_commands_then_usernames() {
+        // Arrange
+        let commands = CommandTable::default().names();
+        let names = vec!["Davey".to_string(), "Dora".to_string(), "Jude".to_string()];
+
+        // Act
+        let command = complete("/hi", 3, &commands, &names);
+        let usernames = complete("/msg Do", 7, &commands, &names);
+        let mention = complete("Ju", 2, &commands, &names);
+
+        // Assert
+        assert_eq!(command, (0, vec!["/history ".to_string()]));
+        assert_eq!(usernames, (5, vec!["Dora".to_string()]));
+        assert_eq!(mention, (0, vec!["Jude".to_string()]));
+    }
+
+    #[test]
+    fn test_plain_lines_are_messages_and_slash_lines_commands() {
+        let table = CommandTable::default();
+
+        assert_eq!(
+            table.parse("send hello"),
+            Input::Send(Command::SendMessage("send hello".to_string()))
+        );
+        assert_eq!(
+            table.parse("//shrug"),
+            Input::Send(Command::SendMessage("/shrug".to_string()))
+        );
+        assert_eq!(
+            table.parse("/msg Jude hey there"),
+            Input::Send(Command::PrivateMessage {
+                to: "Jude".to_string(),
+                text: "hey there".to_string(),
+            })
+        );
+        assert_eq!(table.parse("/leave"), Input::Send(Command::Leave));
+        assert_eq!(
+            table.parse("/nick"),
+            Input::Print("Usage: /nick <NAME>".to_string())
+        );
+        assert_eq!(
+            table.parse("/dance"),
+            Input::Print("Unknown command /dance, try /help".to_string())
+        );
+    }
+
+    #[test]
+    fn test_help_lists_registered_commands() {
+        // Arrange
+        let mut table = CommandTable::new();
+        table.register(SlashCommand {
+            name: "me",
+            args: "<ACTION>",
+            about: "Describe what you are doing",
+            run: |args| Some(Outcome::Send(Command::SendMessage(format!("* {}", args)))),
+        });
+        table.register(SlashCommand {
+            name: "help",
+            args: "",
+            about: "Show this list",
+            run: |_| Some(Outcome::Help),
+        });
+
+        // Act
+        let me = table.parse("/me waves");
+        let help = table.parse("/help");
+
+        // Assert
+        assert_eq!(me, Input::Send(Command::SendMessage("* waves".to_string())));
+        assert_eq!(
+            help,
+            Input::Print(
+                "Type a message to send it to your room. Commands:\n  \
+                 /me <ACTION>             Describe what you are doing\n  \
+                 /help                    Show this list"
+                    .to_string()
+            )
+        );
+    }
 }
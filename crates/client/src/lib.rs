@@ -1,86 +1,263 @@
-use std::{error::Error, sync::Arc};
+use std::error::Error;
+mod chat_client;
 mod cli;
-use cli::Args;
-use common::{command::Command, connection::Connection};
-use log::debug;
-use tokio::sync::Notify;
-use tokio::{
-    net::TcpStream,
-    sync::{mpsc, Mutex},
-    task,
+mod tui;
+pub use chat_client::{ChatClient, ClientResult};
+use cli::Prompt;
+pub use cli::{Args, CommandTable, Input, Outcome, SlashCommand};
+use common::{
+    codec::{Codec, LineCodec},
+    command::Command,
+    connection::Connection,
+    event::{time_of_day, LeaveReason, ServerEvent},
 };
+use futures_util::StreamExt;
+use tokio::{net::TcpStream, sync::mpsc};
 
 pub async fn run(address: String) -> Result<(), Box<dyn Error + Sync + Send>> {
-    let opts: Args = <Args as clap::Parser>::parse();
+    run_with_codec(address, LineCodec::default()).await
+}
+
+pub async fn run_with_codec<C>(
+    address: String,
+    codec: C,
+) -> Result<(), Box<dyn Error + Sync + Send>>
+where
+    C: Codec + Clone + Send + 'static,
+    C::Item: Send,
+{
+    run_with_args(<Args as clap::Parser>::parse(), address, codec).await
+}
+
+pub async fn run_with_args<C>(
+    opts: Args,
+    address: String,
+    codec: C,
+) -> Result<(), Box<dyn Error + Sync + Send>>
+where
+    C: Codec + Clone + Send + 'static,
+    C::Item: Send,
+{
+    run_with_commands(opts, CommandTable::default(), address, codec).await
+}
+
+/**
+ * Runs the client, speaking `codec` to the server: joins with the name from `opts`, then prints what
+ * the server sends while passing on what is typed, read with `commands`, until we leave or the
+ * server goes away.
+ */
+pub async fn run_with_commands<C>(
+    opts: Args,
+    commands: CommandTable,
+    address: String,
+    codec: C,
+) -> Result<(), Box<dyn Error + Sync + Send>>
+where
+    C: Codec + Clone + Send + 'static,
+    C::Item: Send,
+{
     println!("Greetings, {:?}!", opts.username);
-    let socket = TcpStream::connect(address.clone()).await;
-    let connection = Arc::new(Mutex::new(Connection::<TcpStream>::new(socket?)));
+    let socket = TcpStream::connect(address.clone()).await?;
+    let rename = async |event: &ServerEvent| {
+        println!("{}", render_event(event));
+        cli::prompt_username().await
+    };
+    let mut client =
+        ChatClient::start(Connection::new(socket, codec), &opts.username, rename).await?;
+    if opts.tui {
+        return tui::run(client, commands, &address).await;
+    }
+    println!("Joined server at: {:?}", address);
 
     let (tx, mut rx) = mpsc::channel::<Command>(1024);
-    let sender_ref = Arc::new(tx);
-    let connection_clone = connection.clone();
-
-    let notify = Arc::new(Notify::new());
-    let notify_for_join = notify.clone();
-    let notify_for_cli = notify.clone();
-
-    let connection_handle = task::spawn(async move {
-        let tt_connection = connection.clone();
-        tokio::spawn(async move {
-            let mut connection_lock = tt_connection.lock().await;
-            connection_lock
-                .send_command(Command::Join(opts.username))
-                .await
-                .unwrap();
-            println!("Joined server at: {:?}", address.clone());
-            notify_for_join.notify_one();
-            notify_for_join.notify_one();
-        });
-
-        notify.notified().await;
-
-        while let Some(command) = rx.recv().await {
-            let tt_connection = connection.clone();
-            tokio::spawn(async move {
-                let mut connection_lock = tt_connection.lock().await;
-                debug!("Sent command : {:?}", command.to_string().clone());
-                connection_lock.send_command(command).await.unwrap();
-            });
-        }
+    let mut prompt = Prompt::start(commands, tx)?;
+    // Learn who is online for tab completion
+    client.command(Command::ListUsers).await?;
+    prompt.expect_quiet_user_list();
 
-        tokio::spawn(async move {
-            loop {
-                match connection_clone.lock().await.read_command().await {
-                    Ok(Some(command)) => {
-                        match command {
-                            Command::UsernameTaken => println!("That username has been taken, please restart the client with a different one!"),
-                            Command::SendMessage(message) => println!("message: {}", message),
-                            _ => ()
-                        }
-                    }
-                    Ok(None) => {
-                        println!("Connection closed by server");
-                        break;
-                    }
-                    Err(e) => {
-                        println!("Failed to read command from server: {}", e);
-                        return Err::<(), Box<dyn Error + Sync + Send + 'static>>(Box::new(e));
-                    }
+    loop {
+        tokio::select! {
+            event = client.next() => match event {
+                Some(event) => prompt.show(&event),
+                None => {
+                    prompt.print("Connection closed by server, press Enter to exit".to_string());
+                    prompt.stop();
+                    // Wait for the prompt to hand the terminal back
+                    while rx.recv().await.is_some() {}
+                    return Ok(());
                 }
-            }
-            Ok::<(), Box<dyn Error + Sync + Send + 'static>>(())
-        });
-    });
-
-    let cli_handle = task::spawn(async move {
-        notify_for_cli.notified().await;
-        loop {
-            cli::run_cli(sender_ref.clone()).await;
+            },
+            command = rx.recv() => match command {
+                Some(Command::Leave) | None => return client.leave().await,
+                Some(command) => client.command(command).await?,
+            },
         }
-    });
+    }
+}
+
+/**
+ * Formats an event from the server for display in the terminal.
+ */
+fn render_event(event: &ServerEvent) -> String {
+    match event {
+        ServerEvent::HelloAck {
+            version, server, ..
+        } => format!("Connected to {} (protocol v{})", server, version),
+        ServerEvent::UnsupportedVersion {
+            requested,
+            supported,
+        } => format!(
+            "The server does not support protocol v{} (it speaks v{}), please upgrade your client",
+            requested, supported
+        ),
+        ServerEvent::Welcome(username) => format!("Welcome to the chat, {}!", username),
+        ServerEvent::Message {
+            room,
+            from,
+            text,
+            timestamp,
+        } => format!("[{}] {} {}: {}", time_of_day(*timestamp), room, from, text),
+        ServerEvent::PrivateMessage {
+            from,
+            text,
+            timestamp,
+        } => format!("[{}] (private) {}: {}", time_of_day(*timestamp), from, text),
+        ServerEvent::RoomJoined(room) => format!("* You are now in {}", room),
+        ServerEvent::RoomList(rooms) => {
+            let entries: Vec<String> = rooms
+                .iter()
+                .map(|(room, count)| format!("{} ({})", room, count))
+                .collect();
+            format!("Rooms: {}", entries.join(", "))
+        }
+        ServerEvent::UserList(users) => {
+            let entries: Vec<String> = users
+                .iter()
+                .map(|user| {
+                    format!(
+                        "{} (joined {}, idle {})",
+                        user.username,
+                        time_of_day(user.joined_at),
+                        format_duration(user.idle_secs)
+                    )
+                })
+                .collect();
+            format!("Online ({}): {}", users.len(), entries.join(", "))
+        }
+        ServerEvent::UserJoined(username) => format!("* {} joined the chat", username),
+        ServerEvent::UserLeft {
+            username,
+            reason: LeaveReason::Quit,
+        } => format!("* {} left the chat", username),
+        ServerEvent::UserLeft {
+            username,
+            reason: LeaveReason::Disconnected,
+        } => format!("* {} left the chat (connection lost)", username),
+        ServerEvent::UserRenamed { from, to } => format!("* {} is now known as {}", from, to),
+        ServerEvent::UsernameTaken => "That username has been taken.".to_string(),
+        ServerEvent::InvalidUsername(reason) => {
+            format!("That username isn't allowed: {}.", reason)
+        }
+        ServerEvent::History(event) => format!("\x1b[2m{}\x1b[0m", render_event(event)),
+        ServerEvent::ShuttingDown => "* The server is shutting down".to_string(),
+        ServerEvent::Error(reason) => format!("Error from server: {}", reason),
+    }
+}
+
+/**
+ * Renders a span of seconds in its largest whole unit, e.g. `42s`, `5m` or `3h`.
+ */
+fn format_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3_599 => format!("{}m", secs / 60),
+        _ => format!("{}h", secs / 3_600),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::event::OnlineUser;
+
+    #[test]
+    fn test_render_message_shows_time_and_sender() {
+        let event = ServerEvent::Message {
+            room: "#lobby".to_string(),
+            from: "Davey".to_string(),
+            text: "Hey!".to_string(),
+            timestamp: 3_723,
+        };
+
+        assert_eq!(render_event(&event), "[01:02:03] #lobby Davey: Hey!");
+    }
+
+    #[test]
+    fn test_render_private_message_is_marked() {
+        let event = ServerEvent::PrivateMessage {
+            from: "Davey".to_string(),
+            text: "psst".to_string(),
+            timestamp: 3_723,
+        };
+
+        assert_eq!(render_event(&event), "[01:02:03] (private) Davey: psst");
+    }
+
+    #[test]
+    fn test_render_user_list() {
+        let event = ServerEvent::UserList(vec![
+            OnlineUser {
+                username: "Davey".to_string(),
+                joined_at: 3_723,
+                idle_secs: 42,
+            },
+            OnlineUser {
+                username: "Jude".to_string(),
+                joined_at: 0,
+                idle_secs: 7_200,
+            },
+        ]);
+
+        assert_eq!(
+            render_event(&event),
+            "Online (2): Davey (joined 01:02:03, idle 42s), Jude (joined 00:00:00, idle 2h)"
+        );
+    }
+
+    #[test]
+    fn test_render_history_is_dimmed() {
+        let event = ServerEvent::History(Box::new(ServerEvent::Message {
+            room: "#lobby".to_string(),
+            from: "Davey".to_string(),
+            text: "earlier".to_string(),
+            timestamp: 3_723,
+        }));
+
+        assert_eq!(
+            render_event(&event),
+            "\x1b[2m[01:02:03] #lobby Davey: earlier\x1b[0m"
+        );
+    }
 
-    match tokio::join!(connection_handle, cli_handle) {
-        (Ok(_res1), Ok(_res2)) => Ok(()),
-        (Err(e), _) | (_, Err(e)) => Err(Box::new(e)),
+    #[test]
+    fn test_render_presence_events() {
+        assert_eq!(
+            render_event(&ServerEvent::UserJoined("Davey".to_string())),
+            "* Davey joined the chat"
+        );
+        assert_eq!(
+            render_event(&ServerEvent::UserLeft {
+                username: "Davey".to_string(),
+                reason: LeaveReason::Quit,
+            }),
+            "* Davey left the chat"
+        );
+        assert_eq!(
+            render_event(&ServerEvent::UserLeft {
+                username: "Davey".to_string(),
+                reason: LeaveReason::Disconnected,
+            }),
+            "* Davey left the chat (connection lost)"
+        );
     }
 }
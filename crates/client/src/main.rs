@@ -1,18 +1,31 @@
 use std::error::Error;
 
-use client::run;
-use common::config::get_config;
+use clap::Parser;
+use client::{run_with_args, Args};
+#[cfg(feature = "binary")]
+use common::codec::BinaryCodec;
+use common::{
+    codec::{LineCodec, WireFormat},
+    config::load_config,
+};
 use log::debug;
 
 #[tokio::main]
 pub async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     env_logger::init();
 
-    match get_config() {
+    let args = Args::parse();
+    match load_config(&args.config) {
         Ok(config_values) => {
             let address = format!("{}:{}", config_values.host, config_values.port);
 
-            match run(address).await {
+            let result = match config_values.wire_format {
+                WireFormat::Text => run_with_args(args, address, LineCodec::text()).await,
+                WireFormat::Json => run_with_args(args, address, LineCodec::json()).await,
+                #[cfg(feature = "binary")]
+                WireFormat::Binary => run_with_args(args, address, BinaryCodec::new()).await,
+            };
+            match result {
                 Ok(_) => Ok(()),
                 Err(e) => {
                     debug!("Error running the client: {}", e);
@@ -20,9 +33,6 @@ pub async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
                 }
             }
         }
-        Err(_e) => {
-            eprintln!("Error: Your environment variables are not set for HOST and PORT. Exiting.");
-            Ok(())
-        }
+        Err(e) => Err(format!("Couldn't load the settings: {}", e).into()),
     }
 }
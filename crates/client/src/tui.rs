@@ -0,0 +1,477 @@
+use common::{command::Command, event::ServerEvent, protocol::DEFAULT_ROOM};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures_util::StreamExt;
+use ratatui::{
+    layout::{Constraint, Layout, Position},
+    style::{Modifier, Style},
+    text::Line,
+    widgets::{Block, List, Paragraph, Wrap},
+    DefaultTerminal, Frame,
+};
+use unicode_width::UnicodeWidthChar;
+
+use crate::{
+    cli::{CommandTable, Input},
+    render_event, ChatClient, ClientResult,
+};
+
+/**
+ * How many lines the message pane keeps; older ones are forgotten.
+ */
+const SCROLLBACK: usize = 1_000;
+
+const SIDEBAR_WIDTH: u16 = 24;
+
+/**
+ * Runs the full-screen client until we leave, press Esc or Ctrl-C, or the server goes away and a key
+ * is pressed. The terminal is put back the way it was on the way out.
+ */
+pub async fn run(client: ChatClient, commands: CommandTable, address: &str) -> ClientResult<()> {
+    let mut terminal = ratatui::init();
+    let result = run_app(&mut terminal, client, commands, address).await;
+    ratatui::restore();
+    result
+}
+
+async fn run_app(
+    terminal: &mut DefaultTerminal,
+    mut client: ChatClient,
+    commands: CommandTable,
+    address: &str,
+) -> ClientResult<()> {
+    let mut app = App::new(commands, address, client.username());
+    let mut keys = EventStream::new();
+    // Fill the sidebar straight away; later presence changes refresh it
+    client.command(Command::ListUsers).await?;
+    app.quiet_user_lists += 1;
+
+    loop {
+        terminal.draw(|frame| app.draw(frame))?;
+        tokio::select! {
+            event = client.next(), if app.connected => match event {
+                Some(event) => {
+                    if app.handle_event(event) {
+                        client.command(Command::ListUsers).await?;
+                    }
+                }
+                None => app.disconnect(),
+            },
+            key = keys.next() => match key {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                    if !app.connected {
+                        return Ok(());
+                    }
+                    match app.handle_key(key) {
+                        Action::None => {}
+                        Action::Send(Command::Leave) | Action::Quit => return client.leave().await,
+                        Action::Send(command) => client.command(command).await?,
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+/**
+ * What a key press asks of the connection.
+ */
+#[derive(Debug, PartialEq)]
+enum Action {
+    None,
+    Send(Command),
+    Quit,
+}
+
+/**
+ * Everything on screen: the message pane, the input line with its history, the sidebar and the status bar.
+ */
+struct App {
+    commands: CommandTable,
+    address: String,
+    username: String,
+    room: String,
+    connected: bool,
+    messages: Vec<Line<'static>>,
+    /** Lines scrolled back from the newest message. */
+    scroll: usize,
+    users: Vec<String>,
+    /** `who` replies we asked for ourselves, which go to the sidebar only. */
+    quiet_user_lists: usize,
+    input: Vec<char>,
+    cursor: usize,
+    sent: Vec<String>,
+    /** Where we are while browsing `sent` with the arrow keys. */
+    recalled: Option<usize>,
+}
+
+impl App {
+    fn new(commands: CommandTable, address: &str, username: &str) -> Self {
+        App {
+            commands,
+            address: address.to_string(),
+            username: username.to_string(),
+            room: DEFAULT_ROOM.to_string(),
+            connected: true,
+            messages: Vec::new(),
+            scroll: 0,
+            users: Vec::new(),
+            quiet_user_lists: 0,
+            input: Vec::new(),
+            cursor: 0,
+            sent: Vec::new(),
+            recalled: None,
+        }
+    }
+
+    fn push_line(&mut self, line: Line<'static>) {
+        if self.messages.len() == SCROLLBACK {
+            self.messages.remove(0);
+        }
+        self.messages.push(line);
+    }
+
+    /**
+     * Takes in an event from the server, returning whether the user list should be fetched again.
+     */
+    fn handle_event(&mut self, event: ServerEvent) -> bool {
+        match &event {
+            ServerEvent::UserList(users) if self.quiet_user_lists > 0 => {
+                self.quiet_user_lists -= 1;
+                self.users = users.iter().map(|user| user.username.clone()).collect();
+                return false;
+            }
+            ServerEvent::UserList(users) => {
+                self.users = users.iter().map(|user| user.username.clone()).collect();
+            }
+            ServerEvent::RoomJoined(room) => self.room = room.clone(),
+            ServerEvent::UserRenamed { from, to } if *from == self.username => {
+                self.username = to.clone();
+            }
+            _ => {}
+        }
+        let line = match &event {
+            ServerEvent::History(inner) => Line::styled(
+                render_event(inner),
+                Style::default().add_modifier(Modifier::DIM),
+            ),
+            event => Line::raw(render_event(event)),
+        };
+        self.push_line(line);
+        let refresh = matches!(
+            event,
+            ServerEvent::UserJoined(_)
+                | ServerEvent::UserLeft { .. }
+                | ServerEvent::UserRenamed { .. }
+        );
+        if refresh {
+            self.quiet_user_lists += 1;
+        }
+        refresh
+    }
+
+    fn disconnect(&mut self) {
+        self.connected = false;
+        self.push_line(Line::raw("Connection closed by server"));
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Action {
+        // Windows reports AltGr as Ctrl+Alt, and AltGr chords are text, not control chords
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL)
+            && !key.modifiers.contains(KeyModifiers::ALT);
+        match key.code {
+            KeyCode::Esc => return Action::Quit,
+            KeyCode::Char('c') if ctrl => return Action::Quit,
+            // Other control chords aren't bound to anything, and aren't text either
+            KeyCode::Char(_) if ctrl => {}
+            KeyCode::Char(c) => {
+                self.input.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.input.len() => {
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.len(),
+            KeyCode::Up => self.recall_older(),
+            KeyCode::Down => self.recall_newer(),
+            KeyCode::PageUp => {
+                self.scroll = (self.scroll + 10).min(self.messages.len().saturating_sub(1));
+            }
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::Enter => return self.submit(),
+            _ => {}
+        }
+        Action::None
+    }
+
+    fn submit(&mut self) -> Action {
+        let line: String = self.input.drain(..).collect();
+        self.cursor = 0;
+        self.recalled = None;
+        self.scroll = 0;
+        let line = line.trim_end().to_string();
+        if line.trim().is_empty() {
+            return Action::None;
+        }
+        let input = self.commands.parse(&line);
+        self.sent.push(line);
+        match input {
+            Input::Send(command) => Action::Send(command),
+            Input::Print(text) => {
+                for line in text.lines() {
+                    self.push_line(Line::raw(line.to_string()));
+                }
+                Action::None
+            }
+        }
+    }
+
+    fn recall_older(&mut self) {
+        let index = match self.recalled {
+            Some(index) => index.saturating_sub(1),
+            None if !self.sent.is_empty() => self.sent.len() - 1,
+            None => return,
+        };
+        self.recall(Some(index));
+    }
+
+    fn recall_newer(&mut self) {
+        match self.recalled {
+            Some(index) if index + 1 < self.sent.len() => self.recall(Some(index + 1)),
+            Some(_) => self.recall(None),
+            None => {}
+        }
+    }
+
+    fn recall(&mut self, index: Option<usize>) {
+        self.recalled = index;
+        self.input = index.map_or_else(Vec::new, |index| self.sent[index].chars().collect());
+        self.cursor = self.input.len();
+    }
+
+    fn status(&self) -> String {
+        if self.connected {
+            format!(
+                " Connected to {} as {} in {} | PgUp/PgDn scroll, Esc quits",
+                self.address, self.username, self.room
+            )
+        } else {
+            format!(
+                " Disconnected from {} | press any key to quit",
+                self.address
+            )
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, input, status] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(3),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [messages, sidebar] =
+            Layout::horizontal([Constraint::Min(10), Constraint::Length(SIDEBAR_WIDTH)])
+                .areas(main);
+
+        // Show the newest lines that fit once wrapped, less however far we've scrolled back
+        let visible = messages.height.saturating_sub(2) as usize;
+        let width = messages.width.saturating_sub(2);
+        let end = self.messages.len().saturating_sub(self.scroll);
+        let mut start = end;
+        let mut rows = 0;
+        while start > 0 && rows < visible {
+            start -= 1;
+            rows += Paragraph::new(self.messages[start].clone())
+                .wrap(Wrap { trim: false })
+                .line_count(width);
+        }
+        // The oldest line shown may only partly fit; scroll its top off
+        let overflow = rows.saturating_sub(visible).min(u16::MAX as usize) as u16;
+        let title = if self.scroll > 0 {
+            format!(" {} (scrolled back {}) ", self.room, self.scroll)
+        } else {
+            format!(" {} ", self.room)
+        };
+        frame.render_widget(
+            Paragraph::new(self.messages[start..end].to_vec())
+                .wrap(Wrap { trim: false })
+                .scroll((overflow, 0))
+                .block(Block::bordered().title(title)),
+            messages,
+        );
+
+        let title = format!(" Online ({}) ", self.users.len());
+        frame.render_widget(
+            List::new(self.users.iter().map(String::as_str)).block(Block::bordered().title(title)),
+            sidebar,
+        );
+
+        // Scroll the input sideways to keep the cursor in the box, counting columns rather than chars
+        let typed: String = self.input.iter().collect();
+        let room = input.width.saturating_sub(2) as usize;
+        let widths: Vec<usize> = self.input.iter().map(|c| c.width().unwrap_or(0)).collect();
+        let before_cursor: usize = widths[..self.cursor].iter().sum();
+        // Skip whole characters, so a wide one is never cut in half
+        let mut offset = 0;
+        for width in &widths[..self.cursor] {
+            if before_cursor - offset < room {
+                break;
+            }
+            offset += width;
+        }
+        frame.render_widget(
+            Paragraph::new(typed)
+                .scroll((0, offset as u16))
+                .block(Block::bordered()),
+            input,
+        );
+        frame.set_cursor_position(Position::new(
+            input.x + 1 + (before_cursor - offset) as u16,
+            input.y + 1,
+        ));
+
+        frame.render_widget(
+            Paragraph::new(self.status()).style(Style::default().add_modifier(Modifier::REVERSED)),
+            status,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::event::OnlineUser;
+    use ratatui::{backend::TestBackend, Terminal};
+
+    fn type_line(app: &mut App, text: &str) -> Action {
+        for c in text.chars() {
+            app.handle_key(KeyEvent::from(KeyCode::Char(c)));
+        }
+        app.handle_key(KeyEvent::from(KeyCode::Enter))
+    }
+
+    #[test]
+    fn test_input_line_edits_and_recalls_history() {
+        // Arrange
+        let mut app = App::new(CommandTable::default(), "127.0.0.1:8080", "Davey");
+
+        // Act
+        let first = type_line(&mut app, "hi");
+        type_line(&mut app, "/who");
+        app.handle_key(KeyEvent::from(KeyCode::Up));
+        app.handle_key(KeyEvent::from(KeyCode::Up));
+        app.handle_key(KeyEvent::from(KeyCode::Backspace));
+        let edited = type_line(&mut app, "o");
+
+        // Assert
+        assert_eq!(first, Action::Send(Command::SendMessage("hi".to_string())));
+        assert_eq!(edited, Action::Send(Command::SendMessage("ho".to_string())));
+        assert!(app.input.is_empty());
+    }
+
+    #[test]
+    fn test_altgr_characters_are_typed_but_control_chords_are_not() {
+        // Arrange
+        let mut app = App::new(CommandTable::default(), "127.0.0.1:8080", "Davey");
+        let altgr = KeyModifiers::CONTROL | KeyModifiers::ALT;
+
+        // Act
+        app.handle_key(KeyEvent::new(KeyCode::Char('@'), altgr));
+        app.handle_key(KeyEvent::new(KeyCode::Char('w'), KeyModifiers::CONTROL));
+        let quit = app.handle_key(KeyEvent::new(KeyCode::Char('c'), altgr));
+
+        // Assert
+        assert_eq!(quit, Action::None);
+        assert_eq!(app.input, vec!['@', 'c']);
+    }
+
+    #[test]
+    fn test_presence_refreshes_the_sidebar_quietly() {
+        // Arrange
+        let mut app = App::new(CommandTable::default(), "127.0.0.1:8080", "Davey");
+        let users = vec![OnlineUser {
+            username: "Jude".to_string(),
+            joined_at: 0,
+            idle_secs: 0,
+        }];
+
+        // Act
+        let refresh = app.handle_event(ServerEvent::UserJoined("Jude".to_string()));
+        app.handle_event(ServerEvent::UserList(users));
+
+        // Assert
+        assert!(refresh);
+        assert_eq!(app.users, vec!["Jude".to_string()]);
+        // Only the join made it into the message pane
+        assert_eq!(app.messages.len(), 1);
+    }
+
+    #[test]
+    fn test_draw_shows_messages_sidebar_and_status() {
+        // Arrange
+        let mut app = App::new(CommandTable::default(), "127.0.0.1:8080", "Davey");
+        app.users = vec!["Davey".to_string()];
+        app.handle_event(ServerEvent::RoomJoined("#rust".to_string()));
+        let mut terminal = Terminal::new(TestBackend::new(80, 10)).unwrap();
+
+        // Act
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+
+        // Assert
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(screen.contains("* You are now in #rust"));
+        assert!(screen.contains("Online (1)"));
+        assert!(screen.contains("Connected to 127.0.0.1:8080 as Davey in #rust"));
+    }
+
+    #[test]
+    fn test_long_lines_wrap_and_input_scrolls_to_the_cursor() {
+        // Arrange
+        let mut app = App::new(CommandTable::default(), "127.0.0.1:8080", "Davey");
+        app.push_line(Line::raw(format!("{}end of message", "word ".repeat(20))));
+        for c in format!("{}typed last", "界".repeat(40)).chars() {
+            app.handle_key(KeyEvent::from(KeyCode::Char(c)));
+        }
+        app.handle_key(KeyEvent::new(KeyCode::Char('w'), KeyModifiers::CONTROL));
+        let mut terminal = Terminal::new(TestBackend::new(60, 12)).unwrap();
+
+        // Act
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+
+        // Assert
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+        assert!(screen.contains("word word end"));
+        assert!(screen.contains("of message"));
+        assert!(screen.contains("typed last"));
+        assert!(!screen.contains("typed lastw"));
+        // Just past the last character, inside the box
+        let cursor = terminal.get_cursor_position().unwrap();
+        assert_eq!(
+            terminal.backend().buffer()[(cursor.x - 1, cursor.y)].symbol(),
+            "t"
+        );
+        assert!(cursor.x < 59);
+    }
+}
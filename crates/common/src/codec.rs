@@ -0,0 +1,419 @@
+use std::fmt::{self, Display};
+
+use bytes::BytesMut;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio_util::codec::{Decoder, Encoder, LinesCodec, LinesCodecError};
+
+use crate::{command::Command, event::ServerEvent};
+
+/**
+ * The longest line or frame a codec reads unless told otherwise, so a peer that never ends one
+ * can't make us buffer without bound.
+ */
+pub const DEFAULT_MAX_FRAME_LENGTH: usize = 8 * 1024 * 1024;
+
+/**
+ * Which codec a server or client speaks, as set in `Config`. Both ends must agree.
+ * `Json` escapes newlines, keywords and Unicode in the payload; `Text` is the original
+ * space-separated form, kept for hand-typed sessions; `Binary` is length-prefixed bincode.
+ */
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    Text,
+    #[default]
+    Json,
+    #[cfg(feature = "binary")]
+    Binary,
+}
+
+/**
+ * A message that can travel over a `Connection`.
+ */
+pub trait WireMessage: Serialize + DeserializeOwned + Sized {
+    fn to_text(&self) -> String;
+    fn from_text(line: &str) -> Option<Self>;
+}
+
+impl WireMessage for Command {
+    fn to_text(&self) -> String {
+        self.to_string()
+    }
+
+    fn from_text(line: &str) -> Option<Self> {
+        Command::parse(line)
+    }
+}
+
+impl WireMessage for ServerEvent {
+    fn to_text(&self) -> String {
+        self.to_string()
+    }
+
+    fn from_text(line: &str) -> Option<Self> {
+        ServerEvent::parse(line)
+    }
+}
+
+/**
+ * Frames bytes on the wire and turns each frame into a message, and back.
+ * Implemented for every framing a `Connection` can be built over.
+ */
+pub trait Codec:
+    Decoder<Error = ConnectionError> + Encoder<<Self as Decoder>::Item, Error = ConnectionError>
+{
+    fn encode_message<M: WireMessage>(
+        &self,
+        message: &M,
+    ) -> Result<<Self as Decoder>::Item, ConnectionError>;
+
+    fn decode_message<M: WireMessage>(
+        &self,
+        frame: <Self as Decoder>::Item,
+    ) -> Result<M, ConnectionError>;
+}
+
+/**
+ * Newline-delimited frames, each holding one message as JSON or in the text form.
+ */
+#[derive(Debug, Clone)]
+pub struct LineCodec {
+    lines: LinesCodec,
+    json: bool,
+}
+
+impl LineCodec {
+    pub fn json() -> Self {
+        LineCodec {
+            lines: LinesCodec::new_with_max_length(DEFAULT_MAX_FRAME_LENGTH),
+            json: true,
+        }
+    }
+
+    pub fn text() -> Self {
+        LineCodec {
+            lines: LinesCodec::new_with_max_length(DEFAULT_MAX_FRAME_LENGTH),
+            json: false,
+        }
+    }
+
+    /**
+     * Refuses lines longer than `max_length` bytes; reading one is an error that ends the connection.
+     */
+    pub fn with_max_length(self, max_length: usize) -> Self {
+        LineCodec {
+            lines: LinesCodec::new_with_max_length(max_length),
+            ..self
+        }
+    }
+}
+
+impl Default for LineCodec {
+    fn default() -> Self {
+        Self::json()
+    }
+}
+
+impl Decoder for LineCodec {
+    type Item = String;
+    type Error = ConnectionError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<String>, ConnectionError> {
+        Ok(self.lines.decode(src)?)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<String>, ConnectionError> {
+        Ok(self.lines.decode_eof(src)?)
+    }
+}
+
+impl Encoder<String> for LineCodec {
+    type Error = ConnectionError;
+
+    fn encode(&mut self, line: String, dst: &mut BytesMut) -> Result<(), ConnectionError> {
+        Ok(self.lines.encode(line, dst)?)
+    }
+}
+
+impl Codec for LineCodec {
+    fn encode_message<M: WireMessage>(&self, message: &M) -> Result<String, ConnectionError> {
+        if self.json {
+            Ok(serde_json::to_string(message)?)
+        } else {
+            Ok(message.to_text())
+        }
+    }
+
+    fn decode_message<M: WireMessage>(&self, line: String) -> Result<M, ConnectionError> {
+        if self.json {
+            Ok(serde_json::from_str(&line)?)
+        } else {
+            M::from_text(&line).ok_or(ConnectionError::Unrecognised)
+        }
+    }
+}
+
+#[cfg(feature = "binary")]
+pub use binary::BinaryCodec;
+
+#[cfg(feature = "binary")]
+mod binary {
+    use super::*;
+    use tokio_util::codec::LengthDelimitedCodec;
+
+    /**
+     * Length-prefixed frames holding bincode-encoded messages. No UTF-8 scanning, and payloads
+     * may contain anything.
+     */
+    #[derive(Debug, Clone)]
+    pub struct BinaryCodec {
+        frames: LengthDelimitedCodec,
+    }
+
+    impl BinaryCodec {
+        pub fn new() -> Self {
+            Self::new_with_max_length(DEFAULT_MAX_FRAME_LENGTH)
+        }
+
+        /**
+         * Refuses frames longer than `max_length` bytes; reading one is an error that ends the connection.
+         */
+        pub fn with_max_length(self, max_length: usize) -> Self {
+            Self::new_with_max_length(max_length)
+        }
+
+        fn new_with_max_length(max_length: usize) -> Self {
+            BinaryCodec {
+                frames: LengthDelimitedCodec::builder()
+                    .max_frame_length(max_length)
+                    .new_codec(),
+            }
+        }
+    }
+
+    impl Default for BinaryCodec {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl Decoder for BinaryCodec {
+        type Item = BytesMut;
+        type Error = ConnectionError;
+
+        fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, ConnectionError> {
+            Ok(self.frames.decode(src)?)
+        }
+    }
+
+    impl Encoder<BytesMut> for BinaryCodec {
+        type Error = ConnectionError;
+
+        fn encode(&mut self, frame: BytesMut, dst: &mut BytesMut) -> Result<(), ConnectionError> {
+            Ok(self.frames.encode(frame.freeze(), dst)?)
+        }
+    }
+
+    impl Codec for BinaryCodec {
+        fn encode_message<M: WireMessage>(&self, message: &M) -> Result<BytesMut, ConnectionError> {
+            Ok(BytesMut::from(&bincode::serialize(message)?[..]))
+        }
+
+        fn decode_message<M: WireMessage>(&self, frame: BytesMut) -> Result<M, ConnectionError> {
+            Ok(bincode::deserialize(&frame)?)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConnectionError {
+    Io(std::io::Error),
+    Lines(LinesCodecError),
+    Json(serde_json::Error),
+    #[cfg(feature = "binary")]
+    Binary(bincode::Error),
+    /** A text line that isn't any message we know. */
+    Unrecognised,
+}
+
+impl ConnectionError {
+    /**
+     * Whether a whole frame was read but didn't hold a message we understand. The stream is still
+     * in step, so the next message can be read.
+     */
+    pub fn is_bad_message(&self) -> bool {
+        match self {
+            ConnectionError::Io(_) | ConnectionError::Lines(_) => false,
+            ConnectionError::Json(_) | ConnectionError::Unrecognised => true,
+            #[cfg(feature = "binary")]
+            ConnectionError::Binary(_) => true,
+        }
+    }
+}
+
+impl Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectionError::Io(e) => write!(f, "{}", e),
+            ConnectionError::Lines(e) => write!(f, "{}", e),
+            ConnectionError::Json(e) => write!(f, "malformed message: {}", e),
+            #[cfg(feature = "binary")]
+            ConnectionError::Binary(e) => write!(f, "malformed message: {}", e),
+            ConnectionError::Unrecognised => write!(f, "unrecognised message"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {}
+
+impl From<std::io::Error> for ConnectionError {
+    fn from(e: std::io::Error) -> Self {
+        ConnectionError::Io(e)
+    }
+}
+
+impl From<LinesCodecError> for ConnectionError {
+    fn from(e: LinesCodecError) -> Self {
+        ConnectionError::Lines(e)
+    }
+}
+
+impl From<serde_json::Error> for ConnectionError {
+    fn from(e: serde_json::Error) -> Self {
+        ConnectionError::Json(e)
+    }
+}
+
+#[cfg(feature = "binary")]
+impl From<bincode::Error> for ConnectionError {
+    fn from(e: bincode::Error) -> Self {
+        ConnectionError::Binary(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_escapes_newlines_and_keywords() {
+        // Arrange
+        let codec = LineCodec::json();
+        let command = Command::SendMessage("leave\nsend join ünïcødé 👋".to_string());
+
+        // Act
+        let line = codec.encode_message(&command).unwrap();
+        let decoded = codec.decode_message::<Command>(line.clone()).unwrap();
+
+        // Assert
+        assert!(!line.contains('\n'));
+        assert_eq!(decoded, command);
+    }
+
+    #[test]
+    fn test_text_keeps_original_form() {
+        let command = Command::SendMessage("Hey!".to_string());
+
+        assert_eq!(
+            LineCodec::text().encode_message(&command).unwrap(),
+            "send Hey!"
+        );
+    }
+
+    #[test]
+    fn test_text_round_trips_every_command() {
+        let codec = LineCodec::text();
+        for command in [
+            Command::Join("Davey".to_string()),
+            Command::Leave,
+            Command::SendMessage("Hey!".to_string()),
+            Command::JoinRoom("#rust".to_string()),
+            Command::LeaveRoom,
+            Command::ListRooms,
+            Command::PrivateMessage {
+                to: "Jude".to_string(),
+                text: "just us".to_string(),
+            },
+            Command::ListUsers,
+            Command::Nick("Dave".to_string()),
+        ] {
+            let line = codec.encode_message(&command).unwrap();
+            assert_eq!(codec.decode_message::<Command>(line).unwrap(), command);
+        }
+    }
+
+    #[test]
+    fn test_history_round_trips_with_and_without_skip() {
+        for codec in [LineCodec::text(), LineCodec::json()] {
+            for skip in [0, 50] {
+                let command = Command::History { count: 50, skip };
+                let line = codec.encode_message(&command).unwrap();
+                assert_eq!(codec.decode_message::<Command>(line).unwrap(), command);
+            }
+        }
+        assert_eq!(
+            LineCodec::json()
+                .decode_message::<Command>(r#"{"History":{"count":5}}"#.to_string())
+                .unwrap(),
+            Command::History { count: 5, skip: 0 }
+        );
+    }
+
+    #[test]
+    fn test_text_hello_round_trips() {
+        let codec = LineCodec::text();
+        for command in [
+            crate::protocol::hello(),
+            Command::Hello {
+                version: 7,
+                capabilities: vec![],
+            },
+        ] {
+            let line = codec.encode_message(&command).unwrap();
+            assert_eq!(codec.decode_message::<Command>(line).unwrap(), command);
+        }
+    }
+
+    #[test]
+    fn test_malformed_json_is_an_error() {
+        assert!(LineCodec::json()
+            .decode_message::<Command>("send Hey!".to_string())
+            .is_err_and(|e| e.is_bad_message()));
+    }
+
+    #[test]
+    fn test_overlong_line_ends_the_connection() {
+        // Arrange
+        let mut codec = LineCodec::text().with_max_length(16);
+        let mut buffer = BytesMut::from(&b"send this line never ends"[..]);
+
+        // Act
+        let result = codec.decode(&mut buffer);
+
+        // Assert
+        assert!(result.is_err_and(|e| !e.is_bad_message()));
+    }
+
+    #[test]
+    fn test_unknown_text_line_is_unrecognised() {
+        assert!(matches!(
+            LineCodec::text().decode_message::<Command>("sned Hey!".to_string()),
+            Err(ConnectionError::Unrecognised)
+        ));
+    }
+
+    #[cfg(feature = "binary")]
+    #[test]
+    fn test_binary_round_trips_any_payload() {
+        // Arrange
+        let codec = BinaryCodec::new();
+        let event = ServerEvent::message("#lobby", "Davey", "line one\nline two\0 — ünïcødé");
+
+        // Act
+        let frame = codec.encode_message(&event).unwrap();
+        let decoded = codec.decode_message::<ServerEvent>(frame).unwrap();
+
+        // Assert
+        assert_eq!(decoded, event);
+    }
+}
@@ -5,60 +5,128 @@ use std::fmt::{self, Display};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum Command {
+    Hello {
+        version: u32,
+        capabilities: Vec<String>,
+    },
     Join(String),
     Leave,
     SendMessage(String),
-    UsernameTaken,
+    JoinRoom(String),
+    LeaveRoom,
+    ListRooms,
+    PrivateMessage {
+        to: String,
+        text: String,
+    },
+    ListUsers,
+    Nick(String),
+    /**
+     * The room's latest `count` messages, leaving out the newest `skip` of them, so repeating it with
+     * `skip` going up by `count` pages back through the room.
+     */
+    History {
+        count: usize,
+        #[serde(default)]
+        skip: usize,
+    },
 }
 
 impl Command {
     pub fn parse(line: &str) -> Option<Command> {
         let parts: Vec<&str> = line.splitn(2, ' ').collect();
         match parts[0] {
+            "hello" => {
+                let mut fields = parts.get(1)?.split(' ');
+                let version = fields.next()?.parse().ok()?;
+                let capabilities = fields
+                    .next()
+                    .map(|caps| caps.split(',').map(|c| c.to_string()).collect())
+                    .unwrap_or_default();
+                Some(Command::Hello {
+                    version,
+                    capabilities,
+                })
+            }
             "join" => parts
                 .get(1)
                 .map(|&username| Command::Join(username.to_string())),
             "leave" => Some(Command::Leave),
-            "username_taken" => Some(Command::UsernameTaken),
             "send" => parts
                 .get(1)
                 .map(|&msg| Command::SendMessage(msg.to_string())),
+            "join_room" => parts
+                .get(1)
+                .map(|&room| Command::JoinRoom(room.to_string())),
+            "leave_room" => Some(Command::LeaveRoom),
+            "list_rooms" => Some(Command::ListRooms),
+            "msg" => parse_private_message(parts.get(1)?),
+            "list_users" => Some(Command::ListUsers),
+            "history" => parse_history(parts.get(1)?),
+            "nick" => parts
+                .get(1)
+                .map(|&username| Command::Nick(username.to_string())),
             _ => None,
         }
     }
 }
-impl AsRef<str> for Command {
-    fn as_ref(&self) -> &str {
-        match self {
-            Command::SendMessage(msg) => Box::leak(format!("send {}", msg).into_boxed_str()),
-            Command::Join(username) => Box::leak(format!("join {}", username).into_boxed_str()),
-            Command::Leave => "leave",
-            Command::UsernameTaken => "username_taken",
-        }
-    }
-}
+/**
+ * The text wire form, which `Command::parse` reads back.
+ */
 impl Display for Command {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Command::SendMessage(msg) => write!(f, "{}", msg),
+            Command::Hello {
+                version,
+                capabilities,
+            } if capabilities.is_empty() => write!(f, "hello {}", version),
+            Command::Hello {
+                version,
+                capabilities,
+            } => write!(f, "hello {} {}", version, capabilities.join(",")),
+            Command::SendMessage(msg) => write!(f, "send {}", msg),
             Command::Join(username) => write!(f, "join {}", username),
             Command::Leave => write!(f, "leave"),
-            Command::UsernameTaken => write!(f, "username_taken"),
+            Command::JoinRoom(room) => write!(f, "join_room {}", room),
+            Command::LeaveRoom => write!(f, "leave_room"),
+            Command::ListRooms => write!(f, "list_rooms"),
+            Command::PrivateMessage { to, text } => write!(f, "msg {} {}", to, text),
+            Command::ListUsers => write!(f, "list_users"),
+            Command::Nick(username) => write!(f, "nick {}", username),
+            Command::History { count, skip: 0 } => write!(f, "history {}", count),
+            Command::History { count, skip } => write!(f, "history {} {}", count, skip),
         }
     }
 }
 
-pub fn parse_command(input: &str) -> Option<Command> {
-    if input.starts_with("send ") {
-        let msg = input.strip_prefix("send ")?.to_string();
-        Some(Command::SendMessage(msg))
-    } else if input == "leave" {
-        Some(Command::Leave)
-    } else if input == "username_taken" {
-        Some(Command::UsernameTaken)
-    } else {
-        None
+/**
+ * Splits `<user> <text>` into a private message; both parts must be present.
+ */
+fn parse_private_message(input: &str) -> Option<Command> {
+    let (to, text) = input.split_once(' ')?;
+    if to.is_empty() || text.is_empty() {
+        return None;
+    }
+    Some(Command::PrivateMessage {
+        to: to.to_string(),
+        text: text.to_string(),
+    })
+}
+
+/**
+ * Reads `<count> [skip]` into a history request.
+ */
+pub fn parse_history(input: &str) -> Option<Command> {
+    let mut fields = input.split_whitespace();
+    let count = fields.next()?.parse().ok()?;
+    let skip = match fields.next() {
+        Some(skip) => skip.parse().ok()?,
+        None => 0,
+    };
+    if fields.next().is_some() {
+        return None;
     }
+    Some(Command::History { count, skip })
 }
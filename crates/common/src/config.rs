@@ -1,16 +1,241 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 use serde::Deserialize;
-use std::error::Error;
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    codec::WireFormat,
+    username::{UsernameChars, UsernameRules},
+};
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
+    #[serde(default = "default_host")]
     pub host: String,
+    #[serde(default = "default_port")]
     pub port: u16,
+    #[serde(default)]
+    pub wire_format: WireFormat,
+    /** The longest line (or binary frame) the server reads from a client before hanging up on it. */
+    #[serde(default = "default_max_line_length")]
+    pub max_line_length: usize,
+    #[serde(default = "default_username_min_len")]
+    pub username_min_len: usize,
+    #[serde(default = "default_username_max_len")]
+    pub username_max_len: usize,
+    #[serde(default)]
+    pub username_chars: UsernameChars,
+    #[serde(default = "default_history_size")]
+    pub history_size: usize,
+    /** Where to keep every message in SQLite. Without it nothing outlives the server. */
+    pub database_path: Option<String>,
+    #[serde(default = "default_history_page_limit")]
+    pub history_page_limit: usize,
+    /** Where to write transcript files. Without it no transcript is kept. */
+    pub transcript_dir: Option<String>,
+    #[serde(default)]
+    pub transcript_format: TranscriptFormat,
+    #[serde(default = "default_transcript_max_bytes")]
+    pub transcript_max_bytes: u64,
+    #[serde(default = "default_transcript_retention")]
+    pub transcript_retention: usize,
+}
+
+/**
+ * How transcript lines are written: one JSON object per line, or `date time room from: text`.
+ */
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TranscriptFormat {
+    Plain,
+    #[default]
+    Jsonl,
+}
+
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+
+pub const DEFAULT_PORT: u16 = 8080;
+
+/**
+ * The settings file read when neither `--config` nor `CONFIG_FILE` names one, if it exists.
+ */
+pub const DEFAULT_CONFIG_FILE: &str = "chat.toml";
+
+/**
+ * The longest line the server reads from a client, unless `MAX_LINE_LENGTH` says otherwise.
+ */
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/**
+ * How many recent messages per room the server replays to newcomers, unless `HISTORY_SIZE` says otherwise.
+ */
+pub const DEFAULT_HISTORY_SIZE: usize = 50;
+
+/**
+ * The most messages one `history <n>` request returns, unless `HISTORY_PAGE_LIMIT` says otherwise.
+ */
+pub const DEFAULT_HISTORY_PAGE_LIMIT: usize = 100;
+
+/**
+ * The size a transcript file may reach before a new one is started, unless `TRANSCRIPT_MAX_BYTES` says otherwise.
+ */
+pub const DEFAULT_TRANSCRIPT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/**
+ * How many transcript files are kept, unless `TRANSCRIPT_RETENTION` says otherwise.
+ */
+pub const DEFAULT_TRANSCRIPT_RETENTION: usize = 30;
+
+impl Config {
+    pub fn username_rules(&self) -> UsernameRules {
+        UsernameRules {
+            min_len: self.username_min_len,
+            max_len: self.username_max_len,
+            chars: self.username_chars,
+        }
+    }
+}
+
+fn default_host() -> String {
+    DEFAULT_HOST.to_string()
+}
+
+fn default_port() -> u16 {
+    DEFAULT_PORT
+}
+
+fn default_max_line_length() -> usize {
+    DEFAULT_MAX_LINE_LENGTH
+}
+
+fn default_username_min_len() -> usize {
+    UsernameRules::default().min_len
+}
+
+fn default_username_max_len() -> usize {
+    UsernameRules::default().max_len
+}
+
+fn default_history_size() -> usize {
+    DEFAULT_HISTORY_SIZE
+}
+
+fn default_history_page_limit() -> usize {
+    DEFAULT_HISTORY_PAGE_LIMIT
+}
+
+fn default_transcript_max_bytes() -> u64 {
+    DEFAULT_TRANSCRIPT_MAX_BYTES
 }
 
+fn default_transcript_retention() -> usize {
+    DEFAULT_TRANSCRIPT_RETENTION
+}
+
+/**
+ * Command line flags both binaries take. They beat every other source of settings.
+ */
+#[derive(clap::Args, Debug, Default, Clone)]
+#[command(about = None, long_about = None)]
+pub struct ConfigArgs {
+    /** Host to connect to, or for the server to listen on. */
+    #[arg(long, conflicts_with = "server")]
+    pub host: Option<String>,
+    /** Port to connect to, or for the server to listen on. */
+    #[arg(long, conflicts_with = "server")]
+    pub port: Option<u16>,
+    /** Host and port at once. */
+    #[arg(long, value_name = "HOST:PORT", value_parser = parse_server)]
+    pub server: Option<(String, u16)>,
+    /** A TOML file of settings, named like the environment variables (`port = 8080`). */
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+}
+
+fn parse_server(server: &str) -> Result<(String, u16), String> {
+    let (host, port) = server
+        .rsplit_once(':')
+        .ok_or_else(|| format!("expected HOST:PORT, got {:?}", server))?;
+    let port = port
+        .parse()
+        .map_err(|_| format!("{:?} is not a valid port", port))?;
+    Ok((host.to_string(), port))
+}
+
+/**
+ * The settings from the environment, a config file if there is one, and the defaults.
+ */
 pub fn get_config() -> Result<Config, Box<dyn Error>> {
-    Ok(envy::from_env::<Config>()?)
+    load_config(&ConfigArgs::default())
+}
+
+/**
+ * Gathers the settings, each taken from the first place that has it: `args`, then the environment,
+ * then the config file (`args.config`, `CONFIG_FILE`, or `chat.toml` if present), then the defaults.
+ */
+pub fn load_config(args: &ConfigArgs) -> Result<Config, Box<dyn Error>> {
+    let file = args
+        .config
+        .clone()
+        .or_else(|| env::var_os("CONFIG_FILE").map(PathBuf::from))
+        .or_else(|| Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|path| path.exists()));
+    let file_settings = match file {
+        Some(path) => read_config_file(&path)?,
+        None => Vec::new(),
+    };
+    layer(file_settings, env::vars(), args)
+}
+
+fn layer(
+    file_settings: Vec<(String, String)>,
+    env_settings: impl Iterator<Item = (String, String)>,
+    args: &ConfigArgs,
+) -> Result<Config, Box<dyn Error>> {
+    // Keyed as envy sees them, so the environment replaces what the file said
+    let mut settings: HashMap<String, String> = HashMap::new();
+    for (key, value) in file_settings.into_iter().chain(env_settings) {
+        settings.insert(key.to_lowercase(), value);
+    }
+    let mut config: Config = envy::from_iter(settings)?;
+    if let Some((host, port)) = &args.server {
+        config.host = host.clone();
+        config.port = *port;
+    }
+    if let Some(host) = &args.host {
+        config.host = host.clone();
+    }
+    if let Some(port) = args.port {
+        config.port = port;
+    }
+    Ok(config)
+}
+
+/**
+ * Reads a flat TOML file into the same key/value form as the environment.
+ */
+fn read_config_file(path: &Path) -> Result<Vec<(String, String)>, Box<dyn Error>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Couldn't read config file {}: {}", path.display(), e))?;
+    let table: toml::Table = contents.parse()?;
+    table
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Float(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                _ => return Err(format!("Config setting {:?} must be a plain value", key).into()),
+            };
+            Ok((key, value))
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -31,5 +256,57 @@ mod tests {
         // Assert
         assert_eq!(config.host, "127.0.0.1");
         assert_eq!(config.port, 8080);
+        assert_eq!(config.username_rules(), UsernameRules::default());
+        assert_eq!(config.history_size, DEFAULT_HISTORY_SIZE);
+        assert_eq!(config.database_path, None);
+        assert_eq!(config.transcript_dir, None);
+        assert_eq!(config.transcript_format, TranscriptFormat::Jsonl);
+    }
+
+    #[test]
+    fn test_command_line_beats_environment_beats_file() {
+        // Arrange
+        let path = env::temp_dir().join(format!("chat-{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "host = \"file.example\"\nport = 1\nhistory_size = 7\nusername_chars = \"any\"\n",
+        )
+        .unwrap();
+        let file_settings = read_config_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let env_settings = [("PORT".to_string(), "2".to_string())];
+        let args = ConfigArgs {
+            host: Some("cli.example".to_string()),
+            ..ConfigArgs::default()
+        };
+
+        // Act
+        let config = layer(file_settings, env_settings.into_iter(), &args).unwrap();
+
+        // Assert
+        assert_eq!(config.host, "cli.example");
+        assert_eq!(config.port, 2);
+        assert_eq!(config.history_size, 7);
+        assert_eq!(config.username_chars, UsernameChars::Any);
+        assert_eq!(config.history_page_limit, DEFAULT_HISTORY_PAGE_LIMIT);
+        assert_eq!(config.max_line_length, DEFAULT_MAX_LINE_LENGTH);
+    }
+
+    #[test]
+    fn test_server_flag_sets_host_and_port_and_defaults_fill_the_rest() {
+        let args = ConfigArgs {
+            server: parse_server("chat.example:7000").ok(),
+            ..ConfigArgs::default()
+        };
+
+        let config = layer(Vec::new(), std::iter::empty(), &args).unwrap();
+        let defaults = layer(Vec::new(), std::iter::empty(), &ConfigArgs::default()).unwrap();
+
+        assert_eq!((config.host.as_str(), config.port), ("chat.example", 7000));
+        assert_eq!(
+            (defaults.host.as_str(), defaults.port),
+            (DEFAULT_HOST, DEFAULT_PORT)
+        );
+        assert!(parse_server("no-port").is_err());
     }
 }
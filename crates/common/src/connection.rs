@@ -1,146 +1,350 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
+use crate::codec::{Codec, ConnectionError, LineCodec, WireMessage};
 use crate::command::Command;
+use crate::event::ServerEvent;
 use futures_util::sink::SinkExt;
+use futures_util::stream::{SplitSink, SplitStream};
 use futures_util::StreamExt;
-use tokio::io::{duplex, AsyncRead, AsyncWrite, AsyncWriteExt};
-use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Framed};
 
-pub struct Connection<S> {
-    pub framed: Framed<S, LinesCodec>,
+/**
+ * A framed connection carrying one message per frame, laid out by the codec `C`.
+ * Generic over the stream so that either a whole socket or one half of a `tokio::io::split`
+ * can be used: reading only needs `AsyncRead`, sending only `AsyncWrite`.
+ */
+pub struct Connection<S, C = LineCodec> {
+    pub framed: Framed<S, C>,
 }
 
-impl<S: AsyncWrite + AsyncRead + Unpin> Connection<S> {
-    pub fn new(stream: S) -> Self {
-        let framed = Framed::<S, LinesCodec>::new(stream, LinesCodec::new());
+impl<S, C> Connection<S, C> {
+    pub fn new(stream: S, codec: C) -> Self {
+        let framed = Framed::new(stream, codec);
         Connection { framed }
     }
-    pub async fn send_command(&mut self, command: Command) -> Result<(), LinesCodecError> {
-        self.framed.send(command).await?;
-        <Framed<S, LinesCodec> as SinkExt<Command>>::flush(&mut self.framed).await?;
-        Ok(())
-    }
-    pub async fn read_command(&mut self) -> Result<Option<Command>, LinesCodecError> {
-        if let Some(result) = self.framed.next().await {
-            match result {
-                Ok(line) => Ok(Command::parse(&line.to_string())),
-                Err(e) => Err(e.into()),
-            }
-        } else {
-            println!("Client closed connection");
-            Ok(None)
-        }
+}
+
+impl<S: AsyncRead + AsyncWrite, C: Codec + Clone> Connection<S, C> {
+    /**
+     * Splits the connection into halves that can be used from separate tasks, so a pending read
+     * never holds up a send. Unlike `tokio::io::split` this keeps any bytes already buffered.
+     */
+    pub fn split(self) -> (ConnectionReader<S, C>, ConnectionWriter<S, C>) {
+        let codec = self.framed.codec().clone();
+        let (sink, stream) = self.framed.split();
+        let reader = ConnectionReader {
+            stream,
+            codec: codec.clone(),
+        };
+        (reader, ConnectionWriter { sink, codec })
     }
 }
-#[tokio::test]
-async fn test_read_send_message_command() {
-    // Arrange
-    let (client, mut server) = tokio::io::duplex(64);
-    let mut connection = Connection::new(client);
-
-    // Act
-    let command_str = "send Hey!";
-    server.write_all(command_str.as_bytes()).await.unwrap();
-    server.write_all(b"\n").await.unwrap();
-    let command = connection.read_command().await.unwrap();
-
-    // Assert
-    assert_eq!(
-        command.unwrap().to_string(),
-        Command::SendMessage("Hey!".to_string()).to_string()
-    );
+
+impl<S: AsyncWrite + Unpin, C: Codec> Connection<S, C> {
+    pub async fn send<M: WireMessage>(&mut self, message: &M) -> Result<(), ConnectionError> {
+        let frame = self.framed.codec().encode_message(message)?;
+        self.framed.send(frame).await
+    }
+    pub async fn send_command(&mut self, command: Command) -> Result<(), ConnectionError> {
+        self.send(&command).await
+    }
+    pub async fn send_event(&mut self, event: ServerEvent) -> Result<(), ConnectionError> {
+        self.send(&event).await
+    }
 }
-#[tokio::test]
-async fn test_read_leave_command() {
-    // Arrange
-    let (client, mut server) = tokio::io::duplex(64);
-    let mut connection = Connection::new(client);
-
-    // Act
-    let command_str = "leave";
-    server.write_all(command_str.as_bytes()).await.unwrap();
-    server.write_all(b"\n").await.unwrap();
-    let command = connection.read_command().await.unwrap();
-
-    // Assert
-    assert_eq!(command.unwrap().to_string(), Command::Leave.to_string());
+
+impl<S: AsyncRead + Unpin, C: Codec> Connection<S, C> {
+    /**
+     * Reads the next message. `Ok(None)` means the stream closed; a frame that holds no message we
+     * understand is an error for which `is_bad_message` is true, and reading can go on after it.
+     */
+    pub async fn read<M: WireMessage>(&mut self) -> Result<Option<M>, ConnectionError> {
+        match self.framed.next().await {
+            Some(Ok(frame)) => self.framed.codec().decode_message(frame).map(Some),
+            Some(Err(e)) => Err(e),
+            None => Ok(None),
+        }
+    }
+    pub async fn read_command(&mut self) -> Result<Option<Command>, ConnectionError> {
+        self.read().await
+    }
+    pub async fn read_event(&mut self) -> Result<Option<ServerEvent>, ConnectionError> {
+        self.read().await
+    }
 }
-#[tokio::test]
-async fn test_read_join_command() {
-    // Arrange
-    let (client, mut server) = tokio::io::duplex(64);
-    let mut connection = Connection::new(client);
-
-    // Act
-    let command_str = "join Davey";
-    server.write_all(command_str.as_bytes()).await.unwrap();
-    server.write_all(b"\n").await.unwrap();
-    let command = connection.read_command().await.unwrap();
-
-    // Assert
-    assert_eq!(
-        command.unwrap().to_string(),
-        Command::Join("Davey".to_string()).to_string()
-    );
+
+/**
+ * The reading half of a split `Connection`.
+ */
+pub struct ConnectionReader<S, C = LineCodec> {
+    stream: SplitStream<Framed<S, C>>,
+    codec: C,
 }
 
-#[tokio::test]
-async fn test_send_send_message_command() {
-    // Arrange
-    let (client, mut server) = duplex(64);
-    let mut connection = Connection::new(client);
+impl<S: AsyncRead + Unpin, C: Codec> ConnectionReader<S, C> {
+    /**
+     * Reads the next message, as `Connection::read` does.
+     */
+    pub async fn read<M: WireMessage>(&mut self) -> Result<Option<M>, ConnectionError> {
+        match self.stream.next().await {
+            Some(Ok(frame)) => self.codec.decode_message(frame).map(Some),
+            Some(Err(e)) => Err(e),
+            None => Ok(None),
+        }
+    }
+    pub async fn read_command(&mut self) -> Result<Option<Command>, ConnectionError> {
+        self.read().await
+    }
+    pub async fn read_event(&mut self) -> Result<Option<ServerEvent>, ConnectionError> {
+        self.read().await
+    }
+}
 
-    // Act
-    let command = Command::SendMessage("Hey!".to_string());
-    connection.send_command(command).await.unwrap();
+/**
+ * The writing half of a split `Connection`.
+ */
+pub struct ConnectionWriter<S, C: Decoder = LineCodec> {
+    sink: SplitSink<Framed<S, C>, C::Item>,
+    codec: C,
+}
 
-    let mut framed = Framed::new(&mut server, LinesCodec::new());
-    let line = framed.next().await.unwrap().unwrap();
+impl<S: AsyncWrite + Unpin, C: Codec> ConnectionWriter<S, C> {
+    pub async fn send<M: WireMessage>(&mut self, message: &M) -> Result<(), ConnectionError> {
+        let frame = self.codec.encode_message(message)?;
+        self.sink.send(frame).await
+    }
+    pub async fn send_command(&mut self, command: Command) -> Result<(), ConnectionError> {
+        self.send(&command).await
+    }
+    pub async fn send_event(&mut self, event: ServerEvent) -> Result<(), ConnectionError> {
+        self.send(&event).await
+    }
 
-    // Assert
-    assert_eq!(
-        Command::parse(&line.to_string()).unwrap().to_string(),
-        "Hey!"
-    );
+    /**
+     * Flushes and shuts down the write side, telling the peer we are done sending.
+     */
+    pub async fn close(&mut self) -> Result<(), ConnectionError> {
+        self.sink.close().await
+    }
 }
 
-#[tokio::test]
-async fn test_send_and_read_subsequent_messages() {
-    // Arrange
-    let (client, server) = tokio::io::duplex(64);
-    let mut connection_client = Connection::new(client); // Use the client side for sending
-    let mut connection_server = Connection::new(server); // Use the server side for reading
-
-    // Act
-    let command1 = Command::SendMessage("Hey".to_string());
-    connection_client.send_command(command1).await.unwrap();
-
-    let command2 = Command::SendMessage("Jude!".to_string());
-    connection_client.send_command(command2).await.unwrap();
-
-    let command3 = Command::SendMessage("Don't let me down".to_string());
-    connection_client.send_command(command3).await.unwrap();
-
-    // Read commands
-    let read_command1 = connection_server
-        .read_command()
-        .await
-        .expect("No more commands to read")
-        .expect("Not a valid command");
-    let read_command2 = connection_server
-        .read_command()
-        .await
-        .expect("No more commands to read")
-        .expect("Not a valid command");
-    let read_command3 = connection_server
-        .read_command()
-        .await
-        .expect("No more commands to read")
-        .expect("Not a valid command");
-
-    // Assert
-    assert_eq!(read_command1.to_string(), "Hey");
-    assert_eq!(read_command2.to_string(), "Jude!");
-    assert_eq!(read_command3.to_string(), "Don't let me down");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncWriteExt};
+    use tokio_util::codec::LinesCodec;
+
+    #[tokio::test]
+    async fn test_read_send_message_command() {
+        // Arrange
+        let (client, mut server) = tokio::io::duplex(64);
+        let mut connection = Connection::new(client, LineCodec::text());
+
+        // Act
+        let command_str = "send Hey!";
+        server.write_all(command_str.as_bytes()).await.unwrap();
+        server.write_all(b"\n").await.unwrap();
+        let command = connection.read_command().await.unwrap();
+
+        // Assert
+        assert_eq!(
+            command.unwrap().to_string(),
+            Command::SendMessage("Hey!".to_string()).to_string()
+        );
+    }
+    #[tokio::test]
+    async fn test_read_leave_command() {
+        // Arrange
+        let (client, mut server) = tokio::io::duplex(64);
+        let mut connection = Connection::new(client, LineCodec::text());
+
+        // Act
+        let command_str = "leave";
+        server.write_all(command_str.as_bytes()).await.unwrap();
+        server.write_all(b"\n").await.unwrap();
+        let command = connection.read_command().await.unwrap();
+
+        // Assert
+        assert_eq!(command.unwrap().to_string(), Command::Leave.to_string());
+    }
+    #[tokio::test]
+    async fn test_read_join_command() {
+        // Arrange
+        let (client, mut server) = tokio::io::duplex(64);
+        let mut connection = Connection::new(client, LineCodec::text());
+
+        // Act
+        let command_str = "join Davey";
+        server.write_all(command_str.as_bytes()).await.unwrap();
+        server.write_all(b"\n").await.unwrap();
+        let command = connection.read_command().await.unwrap();
+
+        // Assert
+        assert_eq!(
+            command.unwrap().to_string(),
+            Command::Join("Davey".to_string()).to_string()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_send_message_command() {
+        // Arrange
+        let (client, mut server) = duplex(64);
+        let mut connection = Connection::new(client, LineCodec::text());
+
+        // Act
+        let command = Command::SendMessage("Hey!".to_string());
+        connection.send_command(command).await.unwrap();
+
+        let mut framed = Framed::new(&mut server, LinesCodec::new());
+        let line = framed.next().await.unwrap().unwrap();
+
+        // Assert
+        assert_eq!(
+            Command::parse(&line.to_string()).unwrap().to_string(),
+            "send Hey!"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_and_read_subsequent_messages() {
+        // Arrange
+        let (client, server) = tokio::io::duplex(64);
+        let mut connection_client = Connection::new(client, LineCodec::text()); // Use the client side for sending
+        let mut connection_server = Connection::new(server, LineCodec::text()); // Use the server side for reading
+
+        // Act
+        let command1 = Command::SendMessage("Hey".to_string());
+        connection_client.send_command(command1).await.unwrap();
+
+        let command2 = Command::SendMessage("Jude!".to_string());
+        connection_client.send_command(command2).await.unwrap();
+
+        let command3 = Command::SendMessage("Don't let me down".to_string());
+        connection_client.send_command(command3).await.unwrap();
+
+        // Read commands
+        let read_command1 = connection_server
+            .read_command()
+            .await
+            .expect("No more commands to read")
+            .expect("Not a valid command");
+        let read_command2 = connection_server
+            .read_command()
+            .await
+            .expect("No more commands to read")
+            .expect("Not a valid command");
+        let read_command3 = connection_server
+            .read_command()
+            .await
+            .expect("No more commands to read")
+            .expect("Not a valid command");
+
+        // Assert
+        assert_eq!(read_command1.to_string(), "send Hey");
+        assert_eq!(read_command2.to_string(), "send Jude!");
+        assert_eq!(read_command3.to_string(), "send Don't let me down");
+    }
+
+    #[tokio::test]
+    async fn test_send_and_read_server_event() {
+        // Arrange
+        let (server, client) = duplex(64);
+        let mut connection_server = Connection::new(server, LineCodec::text());
+        let mut connection_client = Connection::new(client, LineCodec::text());
+
+        // Act
+        let event = ServerEvent::Message {
+            room: "#lobby".to_string(),
+            from: "Davey".to_string(),
+            text: "Hey!".to_string(),
+            timestamp: 42,
+        };
+        connection_server.send_event(event.clone()).await.unwrap();
+        let read_event = connection_client.read_event().await.unwrap();
+
+        // Assert
+        assert_eq!(read_event, Some(event));
+    }
+
+    #[tokio::test]
+    async fn test_json_carries_multiline_unicode_messages() {
+        // Arrange
+        let (client, server) = duplex(256);
+        let mut connection_client = Connection::new(client, LineCodec::json());
+        let mut connection_server = Connection::new(server, LineCodec::json());
+
+        // Act
+        let command = Command::SendMessage("leave\nsend — naïve 🎸".to_string());
+        connection_client
+            .send_command(command.clone())
+            .await
+            .unwrap();
+        connection_client
+            .send_command(Command::Leave)
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(
+            connection_server.read_command().await.unwrap(),
+            Some(command)
+        );
+        assert_eq!(
+            connection_server.read_command().await.unwrap(),
+            Some(Command::Leave)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_split_halves_read_while_writing() {
+        // Arrange
+        let (client, server) = duplex(256);
+        let (mut reader, mut writer) = Connection::new(client, LineCodec::json()).split();
+        let mut connection_server = Connection::new(server, LineCodec::json());
+
+        // Act
+        // The read is already waiting when the send goes out, as it would on another task
+        let pending_read = tokio::spawn(async move { reader.read_event().await });
+        writer
+            .send_command(Command::SendMessage("Hey".to_string()))
+            .await
+            .unwrap();
+        let received = connection_server.read_command().await.unwrap();
+        connection_server
+            .send_event(ServerEvent::Welcome("Davey".to_string()))
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(received, Some(Command::SendMessage("Hey".to_string())));
+        assert_eq!(
+            pending_read.await.unwrap().unwrap(),
+            Some(ServerEvent::Welcome("Davey".to_string()))
+        );
+    }
+
+    #[cfg(feature = "binary")]
+    #[tokio::test]
+    async fn test_binary_codec_round_trips_over_connection() {
+        // Arrange
+        let (client, server) = duplex(256);
+        let mut connection_client = Connection::new(client, crate::codec::BinaryCodec::new());
+        let mut connection_server = Connection::new(server, crate::codec::BinaryCodec::new());
+
+        // Act
+        let command = Command::SendMessage("multi\nline — 🎸".to_string());
+        connection_client
+            .send_command(command.clone())
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(
+            connection_server.read_command().await.unwrap(),
+            Some(command)
+        );
+    }
 }
@@ -0,0 +1,408 @@
+use std::{
+    fmt::{self, Display},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+/**
+ * Everything the server sends to a client. Kept apart from `Command`, which only covers client requests.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ServerEvent {
+    HelloAck {
+        version: u32,
+        server: String,
+        capabilities: Vec<String>,
+    },
+    UnsupportedVersion {
+        requested: u32,
+        supported: u32,
+    },
+    Welcome(String),
+    Message {
+        room: String,
+        from: String,
+        text: String,
+        timestamp: u64,
+    },
+    PrivateMessage {
+        from: String,
+        text: String,
+        timestamp: u64,
+    },
+    RoomJoined(String),
+    RoomList(Vec<(String, usize)>),
+    UserList(Vec<OnlineUser>),
+    UserJoined(String),
+    UserLeft {
+        username: String,
+        reason: LeaveReason,
+    },
+    UserRenamed {
+        from: String,
+        to: String,
+    },
+    UsernameTaken,
+    InvalidUsername(String),
+    /** An earlier event, replayed from the room's history rather than happening now. */
+    History(Box<ServerEvent>),
+    /** The server is closing every connection and going away. */
+    ShuttingDown,
+    Error(String),
+}
+
+/**
+ * One entry in the reply to `who`: a connected user, when they joined (Unix seconds) and how long
+ * they have been idle (seconds).
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct OnlineUser {
+    pub username: String,
+    pub joined_at: u64,
+    pub idle_secs: u64,
+}
+
+/**
+ * Why a user left the chat.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaveReason {
+    /** They sent `leave`. */
+    Quit,
+    /** Their connection dropped or broke without a `leave`. */
+    Disconnected,
+}
+
+impl LeaveReason {
+    fn parse(word: &str) -> Option<LeaveReason> {
+        match word {
+            "quit" => Some(LeaveReason::Quit),
+            "disconnected" => Some(LeaveReason::Disconnected),
+            _ => None,
+        }
+    }
+}
+
+impl Display for LeaveReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LeaveReason::Quit => write!(f, "quit"),
+            LeaveReason::Disconnected => write!(f, "disconnected"),
+        }
+    }
+}
+
+impl ServerEvent {
+    /**
+     * A message from `from` to `room`, stamped with the current time.
+     */
+    pub fn message(room: &str, from: &str, text: &str) -> ServerEvent {
+        ServerEvent::Message {
+            room: room.to_string(),
+            from: from.to_string(),
+            text: text.to_string(),
+            timestamp: now(),
+        }
+    }
+
+    /**
+     * A message sent by `from` to one user only, stamped with the current time.
+     */
+    pub fn private_message(from: &str, text: &str) -> ServerEvent {
+        ServerEvent::PrivateMessage {
+            from: from.to_string(),
+            text: text.to_string(),
+            timestamp: now(),
+        }
+    }
+
+    pub fn parse(line: &str) -> Option<ServerEvent> {
+        let parts: Vec<&str> = line.splitn(2, ' ').collect();
+        match parts[0] {
+            "hello_ack" => {
+                let mut fields = parts.get(1)?.split(' ');
+                let version = fields.next()?.parse().ok()?;
+                let server = fields.next()?.to_string();
+                let capabilities = fields
+                    .next()
+                    .map(|caps| caps.split(',').map(|c| c.to_string()).collect())
+                    .unwrap_or_default();
+                Some(ServerEvent::HelloAck {
+                    version,
+                    server,
+                    capabilities,
+                })
+            }
+            "unsupported_version" => {
+                let mut fields = parts.get(1)?.split(' ');
+                Some(ServerEvent::UnsupportedVersion {
+                    requested: fields.next()?.parse().ok()?,
+                    supported: fields.next()?.parse().ok()?,
+                })
+            }
+            "welcome" => parts
+                .get(1)
+                .map(|&username| ServerEvent::Welcome(username.to_string())),
+            "message" => {
+                let fields: Vec<&str> = parts.get(1)?.splitn(4, ' ').collect();
+                match fields[..] {
+                    [timestamp, room, from, text] => Some(ServerEvent::Message {
+                        room: room.to_string(),
+                        from: from.to_string(),
+                        text: text.to_string(),
+                        timestamp: timestamp.parse().ok()?,
+                    }),
+                    _ => None,
+                }
+            }
+            "private" => {
+                let fields: Vec<&str> = parts.get(1)?.splitn(3, ' ').collect();
+                match fields[..] {
+                    [timestamp, from, text] => Some(ServerEvent::PrivateMessage {
+                        from: from.to_string(),
+                        text: text.to_string(),
+                        timestamp: timestamp.parse().ok()?,
+                    }),
+                    _ => None,
+                }
+            }
+            "room_joined" => parts
+                .get(1)
+                .map(|&room| ServerEvent::RoomJoined(room.to_string())),
+            "rooms" => {
+                let rooms = parts.get(1).copied().unwrap_or_default();
+                let list = rooms
+                    .split(' ')
+                    .filter(|entry| !entry.is_empty())
+                    .map(|entry| {
+                        let (room, count) = entry.rsplit_once(':')?;
+                        Some((room.to_string(), count.parse().ok()?))
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                Some(ServerEvent::RoomList(list))
+            }
+            "users" => {
+                let users = parts.get(1).copied().unwrap_or_default();
+                let list = users
+                    .split(' ')
+                    .filter(|entry| !entry.is_empty())
+                    .map(|entry| {
+                        let mut fields = entry.rsplitn(3, ':');
+                        let idle_secs = fields.next()?.parse().ok()?;
+                        let joined_at = fields.next()?.parse().ok()?;
+                        Some(OnlineUser {
+                            username: fields.next()?.to_string(),
+                            joined_at,
+                            idle_secs,
+                        })
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                Some(ServerEvent::UserList(list))
+            }
+            "joined" => parts
+                .get(1)
+                .map(|&username| ServerEvent::UserJoined(username.to_string())),
+            "left" => {
+                let (reason, username) = parts.get(1)?.split_once(' ')?;
+                Some(ServerEvent::UserLeft {
+                    username: username.to_string(),
+                    reason: LeaveReason::parse(reason)?,
+                })
+            }
+            "renamed" => {
+                let (from, to) = parts.get(1)?.split_once(' ')?;
+                Some(ServerEvent::UserRenamed {
+                    from: from.to_string(),
+                    to: to.to_string(),
+                })
+            }
+            "username_taken" => Some(ServerEvent::UsernameTaken),
+            "invalid_username" => parts
+                .get(1)
+                .map(|&reason| ServerEvent::InvalidUsername(reason.to_string())),
+            "history" => {
+                ServerEvent::parse(parts.get(1)?).map(|event| ServerEvent::History(Box::new(event)))
+            }
+            "shutting_down" => Some(ServerEvent::ShuttingDown),
+            "error" => parts
+                .get(1)
+                .map(|&reason| ServerEvent::Error(reason.to_string())),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * The wire form of the event, as read back by `ServerEvent::parse`.
+ */
+impl Display for ServerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerEvent::HelloAck {
+                version,
+                server,
+                capabilities,
+            } if capabilities.is_empty() => write!(f, "hello_ack {} {}", version, server),
+            ServerEvent::HelloAck {
+                version,
+                server,
+                capabilities,
+            } => write!(
+                f,
+                "hello_ack {} {} {}",
+                version,
+                server,
+                capabilities.join(",")
+            ),
+            ServerEvent::UnsupportedVersion {
+                requested,
+                supported,
+            } => write!(f, "unsupported_version {} {}", requested, supported),
+            ServerEvent::Welcome(username) => write!(f, "welcome {}", username),
+            ServerEvent::Message {
+                room,
+                from,
+                text,
+                timestamp,
+            } => write!(f, "message {} {} {} {}", timestamp, room, from, text),
+            ServerEvent::PrivateMessage {
+                from,
+                text,
+                timestamp,
+            } => write!(f, "private {} {} {}", timestamp, from, text),
+            ServerEvent::RoomJoined(room) => write!(f, "room_joined {}", room),
+            ServerEvent::RoomList(rooms) => {
+                let entries: Vec<String> = rooms
+                    .iter()
+                    .map(|(room, count)| format!("{}:{}", room, count))
+                    .collect();
+                write!(f, "rooms {}", entries.join(" "))
+            }
+            ServerEvent::UserList(users) => {
+                let entries: Vec<String> = users
+                    .iter()
+                    .map(|user| format!("{}:{}:{}", user.username, user.joined_at, user.idle_secs))
+                    .collect();
+                write!(f, "users {}", entries.join(" "))
+            }
+            ServerEvent::UserJoined(username) => write!(f, "joined {}", username),
+            ServerEvent::UserLeft { username, reason } => {
+                write!(f, "left {} {}", reason, username)
+            }
+            ServerEvent::UserRenamed { from, to } => write!(f, "renamed {} {}", from, to),
+            ServerEvent::UsernameTaken => write!(f, "username_taken"),
+            ServerEvent::InvalidUsername(reason) => write!(f, "invalid_username {}", reason),
+            ServerEvent::History(event) => write!(f, "history {}", event),
+            ServerEvent::ShuttingDown => write!(f, "shutting_down"),
+            ServerEvent::Error(reason) => write!(f, "error {}", reason),
+        }
+    }
+}
+
+/**
+ * Seconds since the Unix epoch.
+ */
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/**
+ * Renders a Unix timestamp as a UTC time of day (HH:MM:SS).
+ */
+pub fn time_of_day(timestamp: u64) -> String {
+    let seconds_today = timestamp % 86_400;
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds_today / 3600,
+        (seconds_today % 3600) / 60,
+        seconds_today % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events_round_trip_through_wire_form() {
+        // Arrange
+        let events = vec![
+            ServerEvent::HelloAck {
+                version: 1,
+                server: "server/0.1.0".to_string(),
+                capabilities: vec!["events".to_string()],
+            },
+            ServerEvent::HelloAck {
+                version: 1,
+                server: "server/0.1.0".to_string(),
+                capabilities: vec![],
+            },
+            ServerEvent::UnsupportedVersion {
+                requested: 2,
+                supported: 1,
+            },
+            ServerEvent::Welcome("Davey".to_string()),
+            ServerEvent::Message {
+                room: "#lobby".to_string(),
+                from: "Davey".to_string(),
+                text: "Hey Jude, don't be afraid".to_string(),
+                timestamp: 1_700_000_000,
+            },
+            ServerEvent::PrivateMessage {
+                from: "Davey".to_string(),
+                text: "just between us".to_string(),
+                timestamp: 1_700_000_000,
+            },
+            ServerEvent::RoomJoined("#rust".to_string()),
+            ServerEvent::RoomList(vec![("#lobby".to_string(), 2), ("#rust".to_string(), 1)]),
+            ServerEvent::RoomList(vec![]),
+            ServerEvent::UserList(vec![OnlineUser {
+                username: "Davey".to_string(),
+                joined_at: 1_700_000_000,
+                idle_secs: 42,
+            }]),
+            ServerEvent::UserJoined("Davey".to_string()),
+            ServerEvent::UserLeft {
+                username: "Davey".to_string(),
+                reason: LeaveReason::Quit,
+            },
+            ServerEvent::UserLeft {
+                username: "Davey".to_string(),
+                reason: LeaveReason::Disconnected,
+            },
+            ServerEvent::UserRenamed {
+                from: "Davey".to_string(),
+                to: "Jude".to_string(),
+            },
+            ServerEvent::UsernameTaken,
+            ServerEvent::InvalidUsername("a username can't be empty".to_string()),
+            ServerEvent::History(Box::new(ServerEvent::Message {
+                room: "#lobby".to_string(),
+                from: "Davey".to_string(),
+                text: "earlier on".to_string(),
+                timestamp: 1_700_000_000,
+            })),
+            ServerEvent::ShuttingDown,
+            ServerEvent::Error("Not a valid command".to_string()),
+        ];
+
+        for event in events {
+            // Act
+            let parsed = ServerEvent::parse(&event.to_string());
+
+            // Assert
+            assert_eq!(parsed, Some(event));
+        }
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_message() {
+        assert_eq!(ServerEvent::parse("message notatime #lobby Davey hi"), None);
+        assert_eq!(ServerEvent::parse("message 12 #lobby Davey"), None);
+        assert_eq!(ServerEvent::parse("shout hi"), None);
+    }
+}
@@ -1,3 +1,7 @@
+pub mod codec;
 pub mod command;
 pub mod config;
 pub mod connection;
+pub mod event;
+pub mod protocol;
+pub mod username;
@@ -0,0 +1,111 @@
+use crate::{command::Command, event::ServerEvent};
+
+/**
+ * The protocol version this build speaks. Bump it whenever a change would confuse older peers.
+ */
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/**
+ * The oldest client version the server still accepts. Version 1 sent `History` as a bare count.
+ */
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/**
+ * Optional features this build understands, announced in the hello exchange.
+ */
+pub const CAPABILITIES: &[&str] = &["events", "rooms"];
+
+/**
+ * The room every user starts in, and returns to when they leave another room.
+ */
+pub const DEFAULT_ROOM: &str = "#lobby";
+
+/**
+ * Normalises a room name to its `#name` form, or `None` if it can't be one.
+ */
+pub fn room_name(input: &str) -> Option<String> {
+    let name = input.trim().trim_start_matches('#');
+    if name.is_empty() || name.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return None;
+    }
+    Some(format!("#{}", name))
+}
+
+/**
+ * The first command a client sends, announcing its version and capabilities.
+ */
+pub fn hello() -> Command {
+    Command::Hello {
+        version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+    }
+}
+
+/**
+ * The server's answer to a client's hello: an acknowledgement carrying the accepted version and the
+ * capabilities both sides share, or the reason the client can't be served.
+ */
+pub fn negotiate(version: u32, capabilities: &[String], server: &str) -> ServerEvent {
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        return ServerEvent::UnsupportedVersion {
+            requested: version,
+            supported: PROTOCOL_VERSION,
+        };
+    }
+    ServerEvent::HelloAck {
+        version,
+        server: server.to_string(),
+        capabilities: capabilities
+            .iter()
+            .filter(|c| CAPABILITIES.contains(&c.as_str()))
+            .cloned()
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate_accepts_current_version_and_shared_capabilities() {
+        // Arrange
+        let capabilities = vec!["events".to_string(), "telepathy".to_string()];
+
+        // Act
+        let reply = negotiate(PROTOCOL_VERSION, &capabilities, "server/0.1.0");
+
+        // Assert
+        assert_eq!(
+            reply,
+            ServerEvent::HelloAck {
+                version: PROTOCOL_VERSION,
+                server: "server/0.1.0".to_string(),
+                capabilities: vec!["events".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_room_name_normalises_and_rejects() {
+        assert_eq!(room_name("rust"), Some("#rust".to_string()));
+        assert_eq!(room_name(" #rust "), Some("#rust".to_string()));
+        assert_eq!(room_name("#"), None);
+        assert_eq!(room_name("two words"), None);
+    }
+
+    #[test]
+    fn test_negotiate_rejects_unknown_versions() {
+        assert_eq!(
+            negotiate(PROTOCOL_VERSION + 1, &[], "server/0.1.0"),
+            ServerEvent::UnsupportedVersion {
+                requested: PROTOCOL_VERSION + 1,
+                supported: PROTOCOL_VERSION,
+            }
+        );
+        assert!(matches!(
+            negotiate(0, &[], "server/0.1.0"),
+            ServerEvent::UnsupportedVersion { .. }
+        ));
+    }
+}
@@ -0,0 +1,195 @@
+use std::fmt::{self, Display};
+
+use serde::Deserialize;
+use unicode_normalization::UnicodeNormalization;
+
+/**
+ * Which characters a username may contain, as set in `Config`.
+ * `Word` allows letters and digits from any script plus `_`, `-` and `.`; `Any` allows every
+ * visible character.
+ */
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UsernameChars {
+    #[default]
+    Word,
+    Any,
+}
+
+impl UsernameChars {
+    fn allows(&self, c: char) -> bool {
+        match self {
+            UsernameChars::Word => c.is_alphanumeric() || matches!(c, '_' | '-' | '.'),
+            UsernameChars::Any => !c.is_whitespace() && !c.is_control(),
+        }
+    }
+}
+
+/**
+ * What makes a username acceptable. Lengths count characters, not bytes.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsernameRules {
+    pub min_len: usize,
+    pub max_len: usize,
+    pub chars: UsernameChars,
+}
+
+impl Default for UsernameRules {
+    fn default() -> Self {
+        UsernameRules {
+            min_len: 1,
+            max_len: 32,
+            chars: UsernameChars::Word,
+        }
+    }
+}
+
+/**
+ * A username that has passed `UsernameRules`, kept in Unicode NFC form for display.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Username(String);
+
+impl Username {
+    pub fn parse(input: &str, rules: &UsernameRules) -> Result<Username, UsernameError> {
+        let name: String = input.trim().nfc().collect();
+        let len = name.chars().count();
+        if len == 0 {
+            return Err(UsernameError::Empty);
+        }
+        if len < rules.min_len {
+            return Err(UsernameError::TooShort(rules.min_len));
+        }
+        if len > rules.max_len {
+            return Err(UsernameError::TooLong(rules.max_len));
+        }
+        if let Some(c) = name.chars().find(|&c| !rules.chars.allows(c)) {
+            return Err(UsernameError::InvalidChar(c));
+        }
+        Ok(Username(name))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /**
+     * The form two names are compared in: names with the same key count as the same name.
+     */
+    pub fn key(&self) -> String {
+        fold(&self.0)
+    }
+}
+
+impl Display for Username {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/**
+ * Compatibility-normalises and case-folds a name, so `Davey`, `DAVEY` and `Ｄａｖｅｙ` share one key.
+ */
+pub fn fold(name: &str) -> String {
+    let folded = caseless::default_case_fold_str(&name.nfkc().collect::<String>());
+    folded.nfkc().collect()
+}
+
+/**
+ * Why a username was turned down. The `Display` form is sent to the client as is.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsernameError {
+    Empty,
+    TooShort(usize),
+    TooLong(usize),
+    InvalidChar(char),
+}
+
+impl Display for UsernameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UsernameError::Empty => write!(f, "a username can't be empty"),
+            UsernameError::TooShort(min) => {
+                write!(f, "a username needs at least {} characters", min)
+            }
+            UsernameError::TooLong(max) => {
+                write!(f, "a username can have at most {} characters", max)
+            }
+            UsernameError::InvalidChar(c) => {
+                write!(f, "a username can't contain {:?}", c)
+            }
+        }
+    }
+}
+
+impl std::error::Error for UsernameError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_trims_and_normalises() {
+        // Arrange
+        // "é" as "e" followed by a combining acute accent
+        let decomposed = "  Rene\u{301} ";
+
+        // Act
+        let username = Username::parse(decomposed, &UsernameRules::default()).unwrap();
+
+        // Assert
+        assert_eq!(username.as_str(), "Ren\u{e9}");
+    }
+
+    #[test]
+    fn test_parse_rejects_names_outside_the_rules() {
+        let rules = UsernameRules {
+            min_len: 3,
+            max_len: 8,
+            chars: UsernameChars::Word,
+        };
+
+        assert_eq!(Username::parse("   ", &rules), Err(UsernameError::Empty));
+        assert_eq!(
+            Username::parse("jo", &rules),
+            Err(UsernameError::TooShort(3))
+        );
+        assert_eq!(
+            Username::parse(&"a".repeat(1_000_000), &rules),
+            Err(UsernameError::TooLong(8))
+        );
+        assert_eq!(
+            Username::parse("a b", &rules),
+            Err(UsernameError::InvalidChar(' '))
+        );
+        assert_eq!(
+            Username::parse("bell\u{7}", &rules),
+            Err(UsernameError::InvalidChar('\u{7}'))
+        );
+    }
+
+    #[test]
+    fn test_any_chars_allows_symbols_but_not_whitespace() {
+        let rules = UsernameRules {
+            chars: UsernameChars::Any,
+            ..UsernameRules::default()
+        };
+
+        assert!(Username::parse("<3_rust!", &rules).is_ok());
+        assert!(Username::parse("tab\there", &rules).is_err());
+    }
+
+    #[test]
+    fn test_names_differing_in_case_or_width_share_a_key() {
+        let rules = UsernameRules::default();
+        let keys: Vec<String> = ["Davey", "DAVEY", "Ｄａｖｅｙ"]
+            .iter()
+            .map(|name| Username::parse(name, &rules).unwrap().key())
+            .collect();
+
+        assert!(keys.iter().all(|key| key == "davey"));
+        assert_eq!(fold("Straße"), fold("STRASSE"));
+    }
+}
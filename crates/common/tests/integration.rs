@@ -1,17 +1,486 @@
+use std::sync::Arc;
+
+use client::ChatClient;
+use common::{
+    codec::{Codec, LineCodec},
+    command::Command,
+    connection::Connection,
+    event::{LeaveReason, ServerEvent},
+    protocol::{self, PROTOCOL_VERSION},
+};
+use futures_util::StreamExt;
+use server::{Server, ServerStats, UserPool};
+use tokio::{
+    io::{duplex, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    net::TcpStream,
+};
+
+/**
+ * Says hello and joins as `username`, returning once the server has welcomed us.
+ */
+async fn join<S, C>(connection: &mut Connection<S, C>, username: &str)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Codec,
+{
+    connection.send_command(protocol::hello()).await.unwrap();
+    assert!(matches!(
+        connection.read_event().await.unwrap(),
+        Some(ServerEvent::HelloAck { .. })
+    ));
+    connection
+        .send_command(Command::Join(username.to_string()))
+        .await
+        .unwrap();
+    assert_eq!(
+        connection.read_event().await.unwrap(),
+        Some(ServerEvent::Welcome(username.to_string()))
+    );
+}
+
+/**
+ * Connects a duplex client to the pool, as if it had come in over TCP.
+ */
+fn connect(user_pool: &Arc<UserPool>) -> Connection<DuplexStream> {
+    let (client, server) = duplex(1024);
+    tokio::spawn(server::handle_connection(
+        server,
+        user_pool.clone(),
+        LineCodec::default(),
+    ));
+    Connection::new(client, LineCodec::default())
+}
+
 #[tokio::test]
 async fn test_server_client_interaction() {
     // Arrange...
-    let server_handle = tokio::spawn(async {
-        let _ = server::run("127.0.0.1:8080".to_string()).await;
-    });
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr();
+
+    let mut alice = Connection::new(
+        TcpStream::connect(address).await.unwrap(),
+        LineCodec::default(),
+    );
+    let mut bob = Connection::new(
+        TcpStream::connect(address).await.unwrap(),
+        LineCodec::default(),
+    );
+
+    // Act
+    // Once both are welcomed both are in the pool
+    join(&mut alice, "alice").await;
+    join(&mut bob, "bob").await;
+    let stats = server.stats().await;
+
+    alice
+        .send_command(Command::SendMessage("Hello over TCP".to_string()))
+        .await
+        .unwrap();
+    let received = bob.read_event().await.unwrap();
+    server.shutdown().await.unwrap();
+
+    // Assert
+    assert_eq!(
+        stats,
+        ServerStats {
+            users: 2,
+            rooms: 1,
+            store_dropped: 0,
+            transcript_dropped: 0,
+            transcript_failed: 0,
+        }
+    );
+    assert!(matches!(
+        received,
+        Some(ServerEvent::Message { from, text, .. }) if from == "alice" && text == "Hello over TCP"
+    ));
+    assert!(TcpStream::connect(address).await.is_err());
+}
+
+#[tokio::test]
+async fn test_chat_clients_talk_through_a_server() {
+    // Arrange
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    let mut alice = ChatClient::connect(server.local_addr(), "alice")
+        .await
+        .unwrap();
+    let mut bob = ChatClient::connect(server.local_addr(), "bob")
+        .await
+        .unwrap();
+    let taken = ChatClient::connect(server.local_addr(), "ALICE").await;
+
+    // Act
+    // Each stream opens with the hello and the welcome
+    let bob_greeting = [bob.next().await, bob.next().await];
+    alice.send("Hi Bob").await.unwrap();
+    let bob_received = bob.next().await;
+    bob.leave().await.unwrap();
+    let alice_saw: Vec<ServerEvent> = alice.by_ref().skip(2).take(2).collect().await;
+
+    // Assert
+    assert!(taken.is_err());
+    assert!(matches!(
+        bob_greeting[0],
+        Some(ServerEvent::HelloAck { .. })
+    ));
+    assert_eq!(
+        bob_greeting[1],
+        Some(ServerEvent::Welcome("bob".to_string()))
+    );
+    assert!(matches!(
+        bob_received,
+        Some(ServerEvent::Message { from, text, .. }) if from == "alice" && text == "Hi Bob"
+    ));
+    assert_eq!(
+        alice_saw,
+        vec![
+            ServerEvent::UserJoined("bob".to_string()),
+            ServerEvent::UserLeft {
+                username: "bob".to_string(),
+                reason: LeaveReason::Quit,
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_chat_client_knows_its_name_as_the_server_does() {
+    // Arrange
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    let mut carol = ChatClient::connect(server.local_addr(), " carol ")
+        .await
+        .unwrap();
+
+    // Act
+    let welcomed_as = carol.username().to_string();
+    carol
+        .command(Command::Nick("caroline".to_string()))
+        .await
+        .unwrap();
+    while carol.username() == welcomed_as {
+        carol.next().await.unwrap();
+    }
+
+    // Assert
+    assert_eq!(welcomed_as, "carol");
+    assert_eq!(carol.username(), "caroline");
+}
+
+#[tokio::test]
+async fn test_shutdown_tells_clients_and_returns() {
+    // Arrange
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr();
+    let mut alice = Connection::new(
+        TcpStream::connect(address).await.unwrap(),
+        LineCodec::default(),
+    );
+    join(&mut alice, "alice").await;
+
+    // Act
+    let shutdown = tokio::spawn(server.shutdown());
+    let notice = alice.read_event().await.unwrap();
+    let after = alice.read_event().await.unwrap();
+    let result = tokio::time::timeout(tokio::time::Duration::from_secs(2), shutdown).await;
+
+    // Assert
+    assert_eq!(notice, Some(ServerEvent::ShuttingDown));
+    // The server closed the connection once the notice was written
+    assert_eq!(after, None);
+    assert!(matches!(result, Ok(Ok(Ok(())))));
+    assert!(TcpStream::connect(address).await.is_err());
+}
+
+#[tokio::test]
+async fn test_shutdown_cuts_off_a_client_that_never_reads() {
+    // Arrange
+    let server = Server::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr();
+    let mut alice = Connection::new(
+        TcpStream::connect(address).await.unwrap(),
+        LineCodec::default(),
+    );
+    let mut stalled = Connection::new(
+        TcpStream::connect(address).await.unwrap(),
+        LineCodec::default(),
+    );
+    join(&mut stalled, "stalled").await;
+    join(&mut alice, "alice").await;
+    // Far more than the socket buffers hold, so the writer for `stalled` gets stuck
+    let text = "x".repeat(64 * 1024);
+    for _ in 0..400 {
+        alice
+            .send_command(Command::SendMessage(text.clone()))
+            .await
+            .unwrap();
+    }
+    alice.send_command(Command::ListUsers).await.unwrap();
+    while !matches!(
+        alice.read_event().await.unwrap(),
+        Some(ServerEvent::UserList(_))
+    ) {}
+
+    // Act
+    server.shutdown().await.unwrap();
+    // Once the server has let go of the socket, writing to it is refused
+    let refused = tokio::time::timeout(tokio::time::Duration::from_secs(2), async {
+        while stalled.send_command(Command::ListUsers).await.is_ok() {
+            tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+
+    // Assert
+    assert!(refused.is_ok(), "the server should have closed the socket");
+}
+
+#[tokio::test]
+async fn test_two_duplex_clients_exchange_messages() {
+    // Arrange
+    let user_pool = Arc::new(UserPool::new());
+    let mut alice = connect(&user_pool);
+    let mut bob = connect(&user_pool);
+
+    // Act
+    join(&mut alice, "alice").await;
+    join(&mut bob, "bob").await;
+    let presence = alice.read_event().await.unwrap();
+
+    alice
+        .send_command(Command::SendMessage("Hi Bob".to_string()))
+        .await
+        .unwrap();
+    let bob_received = bob.read_event().await.unwrap();
+
+    bob.send_command(Command::SendMessage("Hi Alice".to_string()))
+        .await
+        .unwrap();
+    let alice_received = alice.read_event().await.unwrap();
+
+    // Assert
+    assert_eq!(presence, Some(ServerEvent::UserJoined("bob".to_string())));
+    assert!(matches!(
+        bob_received,
+        Some(ServerEvent::Message { from, text, .. }) if from == "alice" && text == "Hi Bob"
+    ));
+    assert!(matches!(
+        alice_received,
+        Some(ServerEvent::Message { from, text, .. }) if from == "bob" && text == "Hi Alice"
+    ));
+}
 
-    // Give the server some time to start
-    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+#[tokio::test]
+async fn test_messages_only_reach_the_senders_room() {
+    // Arrange
+    let user_pool = Arc::new(UserPool::new());
+    let mut alice = connect(&user_pool);
+    let mut bob = connect(&user_pool);
+    join(&mut alice, "alice").await;
+    join(&mut bob, "bob").await;
+    alice.read_event().await.unwrap();
+
+    // Act
+    alice
+        .send_command(Command::JoinRoom("#rust".to_string()))
+        .await
+        .unwrap();
+    let joined = alice.read_event().await.unwrap();
+    alice
+        .send_command(Command::SendMessage("Anyone here?".to_string()))
+        .await
+        .unwrap();
+    bob.send_command(Command::ListRooms).await.unwrap();
+    let rooms = bob.read_event().await.unwrap();
+
+    // Assert
+    assert_eq!(joined, Some(ServerEvent::RoomJoined("#rust".to_string())));
+    assert_eq!(
+        rooms,
+        Some(ServerEvent::RoomList(vec![
+            ("#lobby".to_string(), 1),
+            ("#rust".to_string(), 1)
+        ])),
+        "bob should get the room list without alice's #rust message in between"
+    );
+}
+
+#[tokio::test]
+async fn test_room_hears_why_users_left() {
+    // Arrange
+    let user_pool = Arc::new(UserPool::new());
+    let mut alice = connect(&user_pool);
+    let mut bob = connect(&user_pool);
+    let mut carol = connect(&user_pool);
+    join(&mut alice, "alice").await;
+    join(&mut bob, "bob").await;
+    join(&mut carol, "carol").await;
+    alice.read_event().await.unwrap();
+    alice.read_event().await.unwrap();
+
+    // Act
+    bob.send_command(Command::Leave).await.unwrap();
+    let quit = alice.read_event().await.unwrap();
+    drop(carol);
+    let dropped = alice.read_event().await.unwrap();
+
+    // Assert
+    assert_eq!(
+        quit,
+        Some(ServerEvent::UserLeft {
+            username: "bob".to_string(),
+            reason: LeaveReason::Quit,
+        })
+    );
+    assert_eq!(
+        dropped,
+        Some(ServerEvent::UserLeft {
+            username: "carol".to_string(),
+            reason: LeaveReason::Disconnected,
+        })
+    );
+}
+
+#[tokio::test]
+async fn test_dropped_connection_releases_username() {
+    // Arrange
+    let user_pool = Arc::new(UserPool::new());
+    let mut alice = connect(&user_pool);
+    let mut bob = connect(&user_pool);
+    join(&mut alice, "alice").await;
+    join(&mut bob, "bob").await;
+
+    // Act
+    drop(alice);
+    let departure = bob.read_event().await.unwrap();
+    let mut alice_again = connect(&user_pool);
+
+    // Assert
+    assert!(matches!(
+        departure,
+        Some(ServerEvent::UserLeft { username, reason: LeaveReason::Disconnected }) if username == "alice"
+    ));
+    join(&mut alice_again, "alice").await;
+}
+
+#[tokio::test]
+async fn test_taken_username_can_be_retried_on_the_same_connection() {
+    // Arrange
+    let user_pool = Arc::new(UserPool::new());
+    let mut alice = connect(&user_pool);
+    let mut impostor = connect(&user_pool);
+    join(&mut alice, "alice").await;
+
+    // Act
+    impostor.send_command(protocol::hello()).await.unwrap();
+    impostor.read_event().await.unwrap();
+    impostor
+        .send_command(Command::Join("alice".to_string()))
+        .await
+        .unwrap();
+    let rejected = impostor.read_event().await.unwrap();
+    impostor
+        .send_command(Command::Join("alice2".to_string()))
+        .await
+        .unwrap();
+    let accepted = impostor.read_event().await.unwrap();
+
+    // Assert
+    assert_eq!(rejected, Some(ServerEvent::UsernameTaken));
+    assert_eq!(accepted, Some(ServerEvent::Welcome("alice2".to_string())));
+}
+
+#[tokio::test]
+async fn test_invalid_username_is_rejected_with_a_reason() {
+    // Arrange
+    let user_pool = Arc::new(UserPool::new());
+    let mut client = connect(&user_pool);
+    client.send_command(protocol::hello()).await.unwrap();
+    client.read_event().await.unwrap();
+
+    // Act
+    client
+        .send_command(Command::Join("bell\u{7}".to_string()))
+        .await
+        .unwrap();
+    let rejected = client.read_event().await.unwrap();
+    client
+        .send_command(Command::Join("bell".to_string()))
+        .await
+        .unwrap();
+    let accepted = client.read_event().await.unwrap();
+
+    // Assert
+    assert_eq!(
+        rejected,
+        Some(ServerEvent::InvalidUsername(
+            "a username can't contain '\\u{7}'".to_string()
+        ))
+    );
+    assert_eq!(accepted, Some(ServerEvent::Welcome("bell".to_string())));
+}
+
+#[tokio::test]
+async fn test_client_without_hello_gets_an_error() {
+    // Arrange
+    let user_pool = Arc::new(UserPool::new());
+    let mut old_client = connect(&user_pool);
+
+    // Act
+    old_client
+        .send_command(Command::Join("oldtimer".to_string()))
+        .await
+        .unwrap();
+    let reply = old_client.read_event().await.unwrap();
+    let after = old_client.read_event().await.unwrap();
+
+    // Assert
+    assert!(matches!(reply, Some(ServerEvent::Error(reason)) if reason.contains("upgrade")));
+    assert_eq!(after, None, "the server should hang up after the error");
+}
+
+#[tokio::test]
+async fn test_plain_text_client_on_a_json_server_gets_an_error() {
+    // Arrange
+    let user_pool = Arc::new(UserPool::new());
+    let (mut old_client, server) = duplex(1024);
+    tokio::spawn(server::handle_connection(
+        server,
+        user_pool,
+        LineCodec::json(),
+    ));
+
+    // Act
+    old_client.write_all(b"join oldtimer\n").await.unwrap();
+    let mut reply = String::new();
+    old_client.read_to_string(&mut reply).await.unwrap();
+
+    // Assert
+    let reply: ServerEvent = serde_json::from_str(reply.trim_end()).unwrap();
+    assert!(matches!(reply, ServerEvent::Error(reason) if reason.contains("upgrade")));
+}
+
+#[tokio::test]
+async fn test_client_with_unsupported_version_is_told_so() {
+    // Arrange
+    let user_pool = Arc::new(UserPool::new());
+    let mut future_client = connect(&user_pool);
 
-    let client_handle = tokio::spawn(async {
-        let _res = client::run("127.0.0.1:8080".to_string()).await;
-    });
-    let _ = tokio::join!(server_handle, client_handle);
+    // Act
+    future_client
+        .send_command(Command::Hello {
+            version: PROTOCOL_VERSION + 1,
+            capabilities: vec![],
+        })
+        .await
+        .unwrap();
+    let reply = future_client.read_event().await.unwrap();
 
     // Assert
+    assert_eq!(
+        reply,
+        Some(ServerEvent::UnsupportedVersion {
+            requested: PROTOCOL_VERSION + 1,
+            supported: PROTOCOL_VERSION,
+        })
+    );
 }
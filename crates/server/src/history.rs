@@ -0,0 +1,107 @@
+use std::collections::{HashMap, VecDeque};
+
+use common::event::ServerEvent;
+
+/**
+ * The most recent messages in each room, oldest first. Each room keeps at most `capacity`
+ * messages; older ones fall off the front.
+ */
+pub struct History {
+    capacity: usize,
+    rooms: HashMap<String, VecDeque<ServerEvent>>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        History {
+            capacity,
+            rooms: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, room: &str, event: ServerEvent) {
+        if self.capacity == 0 {
+            return;
+        }
+        let messages = self.rooms.entry(room.to_string()).or_default();
+        if messages.len() == self.capacity {
+            messages.pop_front();
+        }
+        messages.push_back(event);
+    }
+
+    /**
+     * The room's messages, tagged as history, ready to send to someone who just arrived.
+     */
+    pub fn replay(&self, room: &str) -> Vec<ServerEvent> {
+        self.recent(room, self.capacity, 0)
+    }
+
+    /**
+     * Up to `limit` of the room's latest messages once the newest `skip` are left out, oldest first
+     * and tagged as history.
+     */
+    pub fn recent(&self, room: &str, limit: usize, skip: usize) -> Vec<ServerEvent> {
+        let Some(messages) = self.rooms.get(room) else {
+            return Vec::new();
+        };
+        let end = messages.len().saturating_sub(skip);
+        messages
+            .range(end.saturating_sub(limit)..end)
+            .map(|event| ServerEvent::History(Box::new(event.clone())))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keeps_only_the_latest_messages() {
+        // Arrange
+        let mut history = History::new(2);
+
+        // Act
+        for text in ["one", "two", "three"] {
+            history.record("#lobby", ServerEvent::message("#lobby", "anon", text));
+        }
+        let replayed = history.replay("#lobby");
+
+        // Assert
+        let texts: Vec<&str> = replayed
+            .iter()
+            .map(|event| match event {
+                ServerEvent::History(inner) => match inner.as_ref() {
+                    ServerEvent::Message { text, .. } => text.as_str(),
+                    _ => panic!("expected a message"),
+                },
+                _ => panic!("expected history"),
+            })
+            .collect();
+        assert_eq!(texts, vec!["two", "three"]);
+        assert!(history.replay("#rust").is_empty());
+    }
+
+    #[test]
+    fn test_zero_capacity_records_nothing() {
+        let mut history = History::new(0);
+
+        history.record("#lobby", ServerEvent::message("#lobby", "anon", "hi"));
+
+        assert!(history.replay("#lobby").is_empty());
+    }
+
+    #[test]
+    fn test_recent_limits_to_the_latest() {
+        let mut history = History::new(5);
+        for text in ["one", "two", "three"] {
+            history.record("#lobby", ServerEvent::message("#lobby", "anon", text));
+        }
+
+        assert_eq!(history.recent("#lobby", 1, 0).len(), 1);
+        assert_eq!(history.recent("#lobby", 10, 0).len(), 3);
+        assert_eq!(history.recent("#lobby", 2, 2).len(), 1);
+        assert!(history.recent("#lobby", 2, 5).is_empty());
+    }
+}
@@ -1,75 +1,406 @@
+mod history;
+mod rooms;
+pub mod storage;
+pub mod transcript;
 mod user;
 mod user_pool;
 
-use std::sync::Arc;
+use std::{future::Future, io, net::SocketAddr, sync::Arc, time::Duration};
 
 use common::{
-    command::{parse_command, Command},
+    codec::{Codec, LineCodec},
+    command::Command,
     connection::Connection,
+    event::ServerEvent,
+    protocol::{self, PROTOCOL_VERSION},
+    username::Username,
 };
-use log::debug;
+use storage::StoreQueue;
 use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::{mpsc, Mutex},
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, ToSocketAddrs},
+    sync::{mpsc, oneshot},
+    task::{JoinHandle, JoinSet},
 };
+use transcript::Transcript;
 use user::User;
-use user_pool::UserPool;
+pub use user_pool::UserPool;
+
+/**
+ * Identifies this server in the hello exchange.
+ */
+const SERVER_INFO: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/**
+ * How long a shutdown waits for connections to flush what is queued for them before they are cut off.
+ */
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
 
 pub async fn run(address: String) -> Result<(), Box<dyn std::error::Error>> {
+    run_with_shutdown(address, std::future::pending()).await
+}
+
+/**
+ * Runs the server until `shutdown` completes, then shuts it down gracefully (see `serve`).
+ */
+pub async fn run_with_shutdown(
+    address: String,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    serve(address, LineCodec::default(), UserPool::new(), shutdown).await
+}
+
+/**
+ * Runs the server, speaking `codec` to every client and admitting them into `user_pool`, until `shutdown`
+ * completes. It then stops accepting, tells every user the server is going away, and gives each connection
+ * up to `SHUTDOWN_GRACE` to write out what is queued for it before returning.
+ */
+pub async fn serve<C>(
+    address: String,
+    codec: C,
+    user_pool: UserPool,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    C: Codec + Clone + Send + 'static,
+    C::Item: Send,
+{
     let listener = TcpListener::bind(&address).await?;
-    println!("Server running on {}", address);
-    let user_pool = Arc::new(UserPool::<TcpStream>::new());
+    println!("Server running on {}", listener.local_addr()?);
+    let shutdown = async {
+        shutdown.await;
+        println!("Server shutting down");
+    };
+    accept_loop(listener, codec, Arc::new(user_pool), shutdown).await?;
+    Ok(())
+}
+
+/**
+ * Accepts connections until `shutdown` completes or accepting fails, then shuts the pool down and waits
+ * up to `SHUTDOWN_GRACE` for the connections to finish.
+ */
+async fn accept_loop<C>(
+    listener: TcpListener,
+    codec: C,
+    user_pool: Arc<UserPool>,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()>
+where
+    C: Codec + Clone + Send + 'static,
+    C::Item: Send,
+{
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+
+    let result = loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((socket, _)) => {
+                    connections.spawn(handle_connection(socket, user_pool.clone(), codec.clone()));
+                }
+                Err(e) => break Err(e),
+            },
+            // Reap finished connections so the set doesn't grow for the life of the server
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = &mut shutdown => break Ok(()),
+        }
+    };
+
+    drop(listener);
+    user_pool.shutdown().await;
+    if let Some(store) = user_pool.store() {
+        if store.dropped() > 0 {
+            eprintln!(
+                "Message store is missing {} messages, dropped while it was behind",
+                store.dropped()
+            );
+        }
+    }
+    if let Some(transcript) = user_pool.transcript() {
+        if transcript.dropped() > 0 || transcript.failed() > 0 {
+            eprintln!(
+                "Transcript is missing messages: {} dropped while the writer was behind, {} failed to write",
+                transcript.dropped(),
+                transcript.failed()
+            );
+        }
+    }
+    let drained = tokio::time::timeout(SHUTDOWN_GRACE, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        eprintln!(
+            "Gave up waiting on {} connections after {:?}",
+            connections.len(),
+            SHUTDOWN_GRACE
+        );
+        connections.shutdown().await;
+    }
+    result
+}
+
+/**
+ * Sets up a server to run in the background. `Server::bind` starts one with the defaults; otherwise
+ * start from `Server::new()`, swap in a codec or pool, then `listen`.
+ */
+pub struct Server<C = LineCodec> {
+    codec: C,
+    user_pool: UserPool,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Server {
+            codec: LineCodec::default(),
+            user_pool: UserPool::new(),
+        }
+    }
+
+    /**
+     * Starts a server with the default codec and pool on `address`. Port 0 picks a free port; ask the
+     * handle for `local_addr` to find out which.
+     */
+    pub async fn bind(address: impl ToSocketAddrs) -> io::Result<ServerHandle> {
+        Server::new().listen(address).await
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C> Server<C>
+where
+    C: Codec + Clone + Send + 'static,
+    C::Item: Send,
+{
+    pub fn with_codec<D>(self, codec: D) -> Server<D> {
+        Server {
+            codec,
+            user_pool: self.user_pool,
+        }
+    }
+
+    pub fn with_user_pool(mut self, user_pool: UserPool) -> Self {
+        self.user_pool = user_pool;
+        self
+    }
+
+    /**
+     * Binds `address` and accepts connections on a background task until the handle is shut down or dropped.
+     */
+    pub async fn listen(self, address: impl ToSocketAddrs) -> io::Result<ServerHandle> {
+        let listener = TcpListener::bind(address).await?;
+        let local_addr = listener.local_addr()?;
+        let user_pool = Arc::new(self.user_pool);
+        let (stop, stopped) = oneshot::channel::<()>();
+        let shutdown = async {
+            // Dropping the handle drops `stop`, which shuts the server down too
+            let _ = stopped.await;
+        };
+        let task = tokio::spawn(accept_loop(
+            listener,
+            self.codec,
+            user_pool.clone(),
+            shutdown,
+        ));
+        Ok(ServerHandle {
+            local_addr,
+            user_pool,
+            stop,
+            task,
+        })
+    }
+}
+
+/**
+ * A running server. Dropping the handle shuts the server down without waiting for it.
+ */
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    user_pool: Arc<UserPool>,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<io::Result<()>>,
+}
+
+/**
+ * A snapshot of a running server.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerStats {
+    /** Users who have joined, not counting connections still choosing a name. */
+    pub users: usize,
+    pub rooms: usize,
+    /** Messages left out of the message store because it had fallen behind. */
+    pub store_dropped: u64,
+    /** Messages left out of the transcript because its writer had fallen behind. */
+    pub transcript_dropped: u64,
+    /** Messages left out of the transcript because writing them failed. */
+    pub transcript_failed: u64,
+}
+
+impl ServerHandle {
+    /**
+     * The address the server is listening on, with the actual port if it was bound to port 0.
+     */
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub async fn stats(&self) -> ServerStats {
+        let transcript = self.user_pool.transcript();
+        ServerStats {
+            users: self.user_pool.user_count().await,
+            rooms: self.user_pool.room_count().await,
+            store_dropped: self.user_pool.store().map_or(0, StoreQueue::dropped),
+            transcript_dropped: transcript.map_or(0, Transcript::dropped),
+            transcript_failed: transcript.map_or(0, Transcript::failed),
+        }
+    }
+
+    /**
+     * Shuts the server down gracefully (see `serve`), returning once it has stopped.
+     */
+    pub async fn shutdown(self) -> io::Result<()> {
+        let _ = self.stop.send(());
+        self.task.await.map_err(io::Error::other)?
+    }
+}
+
+/**
+ * Handles one client connection: greets it, waits for its `join`, adds the user to the pool, then forwards
+ * everything it sends to the pool's dispatcher. Generic over the stream so it can be driven by
+ * `tokio::io::duplex` in tests.
+ */
+pub async fn handle_connection<S, C>(stream: S, user_pool: Arc<UserPool>, codec: C)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+    C: Codec + Clone + Send + 'static,
+    C::Item: Send,
+{
+    // Reads and writes happen on separate halves, so the pool can write to this user while we wait on the socket
+    let (read_half, write_half) = tokio::io::split(stream);
+    let mut connection = Connection::new(read_half, codec.clone());
+    let mut writer = Connection::new(write_half, codec);
+
+    let admitted = async {
+        if !greet(&mut connection, &mut writer).await {
+            return None;
+        }
+        admit(&mut connection, &mut writer, &user_pool).await
+    };
+    let user = tokio::select! {
+        user = admitted => user,
+        _ = user_pool.shutting_down() => None,
+    };
+    let Some((user, queue)) = user else {
+        return;
+    };
+    let replies = user.msg_sender.clone();
+    let (tx_user_to_pool, rx_pool_from_user) = mpsc::channel(200);
+    let reading = async {
+        // Handle the user's incoming commands until they go or the server does
+        tokio::select! {
+            _ = User::handle_commands(&mut connection, tx_user_to_pool, replies) => {}
+            _ = user_pool.shutting_down() => {}
+        }
+    };
+    // Once the dispatcher lets go of the user their queue closes, and the writer stops after sending
+    // what's left. All three run on this task, so aborting it cuts the connection off entirely.
+    tokio::join!(
+        reading,
+        user_pool.dispatch(rx_pool_from_user, user),
+        User::write_messages(queue, writer),
+    );
+}
 
+/**
+ * Waits for a `join` with a valid, free username and adds that user to the pool. An invalid or taken name
+ * is answered with `InvalidUsername` or `UsernameTaken` and the client may try another; anything else
+ * ends the connection.
+ */
+async fn admit<R, W, C>(
+    reader: &mut Connection<R, C>,
+    writer: &mut Connection<W, C>,
+    user_pool: &UserPool,
+) -> Option<(Arc<User>, mpsc::Receiver<ServerEvent>)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    C: Codec,
+{
     loop {
-        let (socket, _) = listener.accept().await?;
-        let user_pool: Arc<UserPool<TcpStream>> = user_pool.clone();
-
-        tokio::spawn(async move {
-            let mut connection = Connection::new(socket);
-
-            match connection.read_command().await {
-                Ok(Some(Command::Join(username))) => {
-                    // Channels for communication
-                    let (tx_user_to_pool, rx_pool_from_user) = mpsc::channel(200);
-                    let (_, user_from_pool) = mpsc::channel::<String>(1024);
-
-                    let connected_user = User {
-                        username: username.clone(),
-                        msg_sender: tx_user_to_pool.clone(),
-                        msg_receiver: Arc::new(Mutex::new(user_from_pool)),
-                        conn: connection,
-                    };
-                    let user = Arc::new(Mutex::new(connected_user));
-                    user_pool.add_user(user.clone()).await;
-
-                    let user_cloned = user.clone();
-                    let user_pool_cloned = user_pool.clone();
-                    // Spawn a task to handle incoming commands from this user's connection
-                    tokio::spawn(async move {
-                        println!("Server processing commands:");
-                        let mut local_rx = rx_pool_from_user; // Take ownership of the receiver
-                        while let Some(message) = local_rx.recv().await {
-                            println!("Server processing command: {:?}", message);
-                            // user_pool_cloned
-                            //     .process_command(parse_command(message.as_str()), user.clone())
-                            //     .await;
-                        }
-                    });
-                    // Spawn a separate task for this user to handle their commands
-                    let user_pool_cloned_for_comm = user_pool.clone();
-                    let user = user_cloned.clone();
-                    tokio::spawn(async move {
-                        user.lock()
-                            .await
-                            .handle_commands(user_pool_cloned_for_comm)
-                            .await;
-                    });
+        match reader.read_command().await {
+            Ok(Some(Command::Join(username))) => {
+                let username = match Username::parse(&username, user_pool.username_rules()) {
+                    Ok(username) => username,
+                    Err(e) => {
+                        let reply = ServerEvent::InvalidUsername(e.to_string());
+                        writer.send_event(reply).await.ok()?;
+                        continue;
+                    }
+                };
+                let (tx_pool_to_user, rx_user_from_pool) = mpsc::channel::<ServerEvent>(1024);
+                let user = Arc::new(User::new(username.to_string(), tx_pool_to_user));
+                // The pool checks and takes the name under one lock, so two clients can't both get it
+                if user_pool.add_user(user.clone()).await {
+                    return Some((user, rx_user_from_pool));
                 }
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("Error reading initial command: {}", e);
+                if user_pool.is_shutting_down() {
+                    return None;
                 }
+                writer.send_event(ServerEvent::UsernameTaken).await.ok()?;
             }
-        });
+            Ok(Some(_)) => {
+                let _ = writer
+                    .send_event(ServerEvent::Error("Expected join <username>".to_string()))
+                    .await;
+                return None;
+            }
+            Ok(None) => return None,
+            Err(e) => {
+                eprintln!("Error reading join command: {}", e);
+                return None;
+            }
+        }
     }
 }
+
+/**
+ * Runs the hello exchange that opens every connection, returning whether the client may go on to join.
+ * Clients that skip it, or speak a version we don't, are told why before being dropped.
+ */
+async fn greet<R, W, C>(reader: &mut Connection<R, C>, writer: &mut Connection<W, C>) -> bool
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    C: Codec,
+{
+    let reply = match reader.read_command().await {
+        Ok(Some(Command::Hello {
+            version,
+            capabilities,
+        })) => protocol::negotiate(version, &capabilities, SERVER_INFO),
+        // An old client may not even speak this wire format; tell it all the same
+        Ok(Some(_)) => upgrade_needed(),
+        Err(e) if e.is_bad_message() => upgrade_needed(),
+        Ok(None) => return false,
+        Err(e) => {
+            eprintln!("Error reading initial command: {}", e);
+            return false;
+        }
+    };
+    let accepted = matches!(reply, ServerEvent::HelloAck { .. });
+    let _ = writer.send_event(reply).await;
+    accepted
+}
+
+fn upgrade_needed() -> ServerEvent {
+    ServerEvent::Error(format!(
+        "Expected hello: this server speaks protocol version {}, please upgrade your client",
+        PROTOCOL_VERSION
+    ))
+}
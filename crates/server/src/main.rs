@@ -1,11 +1,98 @@
-use common::config::get_config;
-use server::run;
-mod user;
-mod user_pool;
+use clap::Parser;
+#[cfg(feature = "binary")]
+use common::codec::BinaryCodec;
+use common::{
+    codec::{LineCodec, WireFormat},
+    config::{load_config, ConfigArgs},
+};
+#[cfg(feature = "sqlite")]
+use server::storage::SqliteStore;
+use server::{
+    serve,
+    transcript::{Transcript, TranscriptSettings},
+    UserPool,
+};
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(flatten)]
+    config: ConfigArgs,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let config_values = get_config()?;
+    let args = Args::parse();
+    let config_values = load_config(&args.config)?;
     let address = format!("{}:{}", config_values.host, config_values.port);
-    run(address).await
+    let user_pool = UserPool::from_config(&config_values);
+    let user_pool = match &config_values.database_path {
+        #[cfg(feature = "sqlite")]
+        Some(path) => {
+            let store = SqliteStore::open(path).map_err(|e| e as Box<dyn std::error::Error>)?;
+            user_pool.with_store(std::sync::Arc::new(store))
+        }
+        #[cfg(not(feature = "sqlite"))]
+        Some(_) => {
+            return Err("DATABASE_PATH is set but this server was built without sqlite".into())
+        }
+        None => user_pool,
+    };
+    let user_pool = match TranscriptSettings::from_config(&config_values) {
+        Some(settings) => user_pool.with_transcript(Transcript::start(settings)),
+        None => user_pool,
+    };
+    let shutdown = shutdown_signal();
+    let max_line_length = config_values.max_line_length;
+    match config_values.wire_format {
+        WireFormat::Text => {
+            serve(
+                address,
+                LineCodec::text().with_max_length(max_line_length),
+                user_pool,
+                shutdown,
+            )
+            .await
+        }
+        WireFormat::Json => {
+            serve(
+                address,
+                LineCodec::json().with_max_length(max_line_length),
+                user_pool,
+                shutdown,
+            )
+            .await
+        }
+        #[cfg(feature = "binary")]
+        WireFormat::Binary => {
+            serve(
+                address,
+                BinaryCodec::new().with_max_length(max_line_length),
+                user_pool,
+                shutdown,
+            )
+            .await
+        }
+    }
+}
+
+/**
+ * Completes on Ctrl-C (SIGINT) or, on Unix, SIGTERM, which is what service managers and containers send.
+ */
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => eprintln!("Couldn't listen for SIGTERM: {}", e),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
 }
@@ -0,0 +1,141 @@
+use std::collections::{HashMap, HashSet};
+
+use common::protocol::DEFAULT_ROOM;
+
+/**
+ * Which users are in which room. Every user is in exactly one room at a time, starting in the
+ * default room; rooms other than the default one disappear when their last member leaves.
+ */
+pub struct Rooms {
+    members: HashMap<String, HashSet<String>>,
+    room_of: HashMap<String, String>,
+}
+
+impl Default for Rooms {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rooms {
+    pub fn new() -> Self {
+        let mut members = HashMap::new();
+        members.insert(DEFAULT_ROOM.to_string(), HashSet::new());
+        Rooms {
+            members,
+            room_of: HashMap::new(),
+        }
+    }
+
+    /**
+     * Moves a user into `room`, creating it if needed, and out of whichever room they were in.
+     */
+    pub fn enter(&mut self, username: &str, room: &str) {
+        self.remove(username);
+        self.members
+            .entry(room.to_string())
+            .or_default()
+            .insert(username.to_string());
+        self.room_of.insert(username.to_string(), room.to_string());
+    }
+
+    /**
+     * Takes a user out of the registry altogether, returning the room they were in.
+     */
+    pub fn remove(&mut self, username: &str) -> Option<String> {
+        let room = self.room_of.remove(username)?;
+        if let Some(members) = self.members.get_mut(&room) {
+            members.remove(username);
+            if members.is_empty() && room != DEFAULT_ROOM {
+                self.members.remove(&room);
+            }
+        }
+        Some(room)
+    }
+
+    /**
+     * Moves a user's membership over to their new name, returning the room they are in.
+     */
+    pub fn rename(&mut self, old_username: &str, new_username: &str) -> Option<String> {
+        let room = self.room_of.remove(old_username)?;
+        if let Some(members) = self.members.get_mut(&room) {
+            members.remove(old_username);
+            members.insert(new_username.to_string());
+        }
+        self.room_of.insert(new_username.to_string(), room.clone());
+        Some(room)
+    }
+
+    pub fn room_of(&self, username: &str) -> Option<&str> {
+        self.room_of.get(username).map(String::as_str)
+    }
+
+    pub fn members(&self, room: &str) -> impl Iterator<Item = &String> {
+        self.members.get(room).into_iter().flatten()
+    }
+
+    /**
+     * Every room with its member count, sorted by name.
+     */
+    pub fn list(&self) -> Vec<(String, usize)> {
+        let mut rooms: Vec<(String, usize)> = self
+            .members
+            .iter()
+            .map(|(room, members)| (room.clone(), members.len()))
+            .collect();
+        rooms.sort();
+        rooms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enter_moves_user_between_rooms() {
+        // Arrange
+        let mut rooms = Rooms::new();
+        rooms.enter("anon", DEFAULT_ROOM);
+
+        // Act
+        rooms.enter("anon", "#rust");
+
+        // Assert
+        assert_eq!(rooms.room_of("anon"), Some("#rust"));
+        assert_eq!(rooms.members(DEFAULT_ROOM).count(), 0);
+        assert_eq!(rooms.members("#rust").collect::<Vec<_>>(), vec!["anon"]);
+    }
+
+    #[test]
+    fn test_empty_rooms_are_dropped_but_lobby_stays() {
+        // Arrange
+        let mut rooms = Rooms::new();
+        rooms.enter("anon", "#rust");
+
+        // Act
+        let left = rooms.remove("anon");
+
+        // Assert
+        assert_eq!(left, Some("#rust".to_string()));
+        assert_eq!(rooms.list(), vec![(DEFAULT_ROOM.to_string(), 0)]);
+    }
+
+    #[test]
+    fn test_list_counts_members() {
+        // Arrange
+        let mut rooms = Rooms::new();
+        rooms.enter("anon", DEFAULT_ROOM);
+        rooms.enter("anon2", "#rust");
+        rooms.enter("anon3", "#rust");
+
+        // Act
+        let listed = rooms.list();
+
+        // Assert
+        assert_eq!(
+            listed,
+            vec![(DEFAULT_ROOM.to_string(), 1), ("#rust".to_string(), 2)]
+        );
+    }
+}
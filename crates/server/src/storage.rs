@@ -0,0 +1,271 @@
+use std::{
+    error::Error,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use common::event::ServerEvent;
+use tokio::sync::{mpsc, oneshot};
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+
+pub type StorageResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/**
+ * How many requests may wait for the store before new messages are dropped.
+ */
+const QUEUE_CAPACITY: usize = 4096;
+
+/**
+ * Somewhere to keep room messages beyond the in-memory history, so they survive a restart.
+ * The pool calls it from a blocking thread, one call at a time (see `StoreQueue`).
+ */
+pub trait MessageStore: Send + Sync {
+    fn record(&self, room: &str, from: &str, text: &str, timestamp: u64) -> StorageResult<()>;
+
+    /**
+     * Up to `limit` of the room's latest messages once the newest `skip` are left out, oldest first.
+     */
+    fn recent(&self, room: &str, limit: usize, skip: usize) -> StorageResult<Vec<ServerEvent>>;
+}
+
+enum Request {
+    Record {
+        room: String,
+        from: String,
+        text: String,
+        timestamp: u64,
+    },
+    Recent {
+        room: String,
+        limit: usize,
+        skip: usize,
+        reply: oneshot::Sender<StorageResult<Vec<ServerEvent>>>,
+    },
+}
+
+/**
+ * The pool's end of a `MessageStore`. Requests are queued to a blocking thread that works through
+ * them in order, so store I/O never runs on the runtime or under the pool's locks, and a read sees
+ * every message recorded before it.
+ */
+pub struct StoreQueue {
+    queue: mpsc::Sender<Request>,
+    dropped: AtomicU64,
+}
+
+impl StoreQueue {
+    /**
+     * Starts the store's thread. Must be called from within a Tokio runtime.
+     */
+    pub fn start(store: Arc<dyn MessageStore>) -> StoreQueue {
+        let (queue, requests) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::task::spawn_blocking(move || serve_requests(store.as_ref(), requests));
+        StoreQueue {
+            queue,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /**
+     * Queues a message to be stored without waiting; if the store has fallen behind it is dropped.
+     */
+    pub fn record(&self, room: &str, from: &str, text: &str, timestamp: u64) {
+        let request = Request::Record {
+            room: room.to_string(),
+            from: from.to_string(),
+            text: text.to_string(),
+            timestamp,
+        };
+        if self.queue.try_send(request).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /**
+     * Messages left out of the store because it had fallen behind.
+     */
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub async fn recent(
+        &self,
+        room: &str,
+        limit: usize,
+        skip: usize,
+    ) -> StorageResult<Vec<ServerEvent>> {
+        let (reply, replied) = oneshot::channel();
+        let request = Request::Recent {
+            room: room.to_string(),
+            limit,
+            skip,
+            reply,
+        };
+        self.queue
+            .send(request)
+            .await
+            .map_err(|_| "The store has stopped")?;
+        replied.await.map_err(|_| "The store has stopped")?
+    }
+}
+
+fn serve_requests(store: &dyn MessageStore, mut requests: mpsc::Receiver<Request>) {
+    while let Some(request) = requests.blocking_recv() {
+        match request {
+            Request::Record {
+                room,
+                from,
+                text,
+                timestamp,
+            } => {
+                if let Err(e) = store.record(&room, &from, &text, timestamp) {
+                    eprintln!("Error storing message: {}", e);
+                }
+            }
+            Request::Recent {
+                room,
+                limit,
+                skip,
+                reply,
+            } => {
+                let _ = reply.send(store.recent(&room, limit, skip));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::{path::Path, sync::Mutex};
+
+    use rusqlite::{params, Connection};
+
+    use super::*;
+
+    /**
+     * Keeps every message in a single SQLite file.
+     */
+    pub struct SqliteStore {
+        connection: Mutex<Connection>,
+    }
+
+    impl SqliteStore {
+        pub fn open(path: impl AsRef<Path>) -> StorageResult<Self> {
+            Self::init(Connection::open(path)?)
+        }
+
+        pub fn open_in_memory() -> StorageResult<Self> {
+            Self::init(Connection::open_in_memory()?)
+        }
+
+        fn init(connection: Connection) -> StorageResult<Self> {
+            connection.execute_batch(
+                "CREATE TABLE IF NOT EXISTS messages (
+                    id INTEGER PRIMARY KEY,
+                    room TEXT NOT NULL,
+                    sender TEXT NOT NULL,
+                    text TEXT NOT NULL,
+                    timestamp INTEGER NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS messages_by_room ON messages (room, id);",
+            )?;
+            Ok(SqliteStore {
+                connection: Mutex::new(connection),
+            })
+        }
+    }
+
+    impl MessageStore for SqliteStore {
+        fn record(&self, room: &str, from: &str, text: &str, timestamp: u64) -> StorageResult<()> {
+            self.connection.lock().unwrap().execute(
+                "INSERT INTO messages (room, sender, text, timestamp) VALUES (?1, ?2, ?3, ?4)",
+                params![room, from, text, timestamp as i64],
+            )?;
+            Ok(())
+        }
+
+        fn recent(&self, room: &str, limit: usize, skip: usize) -> StorageResult<Vec<ServerEvent>> {
+            let connection = self.connection.lock().unwrap();
+            let mut statement = connection.prepare(
+                "SELECT sender, text, timestamp FROM messages
+                 WHERE room = ?1 ORDER BY id DESC LIMIT ?2 OFFSET ?3",
+            )?;
+            let mut messages = statement
+                .query_map(params![room, limit as i64, skip as i64], |row| {
+                    Ok(ServerEvent::Message {
+                        room: room.to_string(),
+                        from: row.get(0)?,
+                        text: row.get(1)?,
+                        timestamp: row.get::<_, i64>(2)? as u64,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            messages.reverse();
+            Ok(messages)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_recent_returns_latest_messages_of_the_room_oldest_first() {
+            // Arrange
+            let store = SqliteStore::open_in_memory().unwrap();
+            store.record("#lobby", "anon", "one", 1).unwrap();
+            store.record("#rust", "anon2", "elsewhere", 2).unwrap();
+            store.record("#lobby", "anon", "two", 3).unwrap();
+            store.record("#lobby", "anon2", "three", 4).unwrap();
+
+            // Act
+            let recent = store.recent("#lobby", 2, 0).unwrap();
+            let older = store.recent("#lobby", 2, 2).unwrap();
+
+            // Assert
+            assert_eq!(
+                recent,
+                vec![
+                    ServerEvent::Message {
+                        room: "#lobby".to_string(),
+                        from: "anon".to_string(),
+                        text: "two".to_string(),
+                        timestamp: 3,
+                    },
+                    ServerEvent::Message {
+                        room: "#lobby".to_string(),
+                        from: "anon2".to_string(),
+                        text: "three".to_string(),
+                        timestamp: 4,
+                    },
+                ]
+            );
+            assert!(matches!(&older[..], [ServerEvent::Message { text, .. }] if text == "one"));
+        }
+
+        #[test]
+        fn test_messages_survive_reopening_the_file() {
+            // Arrange
+            let path = std::env::temp_dir().join(format!("chat-{}.sqlite", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            SqliteStore::open(&path)
+                .unwrap()
+                .record("#lobby", "anon", "still here", 1)
+                .unwrap();
+
+            // Act
+            let recent = SqliteStore::open(&path)
+                .unwrap()
+                .recent("#lobby", 10, 0)
+                .unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            // Assert
+            assert_eq!(recent.len(), 1);
+        }
+    }
+}
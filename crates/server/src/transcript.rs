@@ -0,0 +1,404 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use common::{
+    config::{Config, TranscriptFormat},
+    event::time_of_day,
+};
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+/**
+ * How many messages may wait for the transcript writer before new ones are dropped.
+ */
+const QUEUE_CAPACITY: usize = 4096;
+
+const FILE_PREFIX: &str = "transcript-";
+
+/**
+ * Where and how transcripts are written.
+ */
+#[derive(Debug, Clone)]
+pub struct TranscriptSettings {
+    pub dir: PathBuf,
+    pub format: TranscriptFormat,
+    /** A file is rotated before it would grow past this many bytes. */
+    pub max_bytes: u64,
+    /** How many transcript files to keep; the oldest are deleted beyond that. */
+    pub retention: usize,
+}
+
+impl TranscriptSettings {
+    /**
+     * The settings in `config`, or `None` if transcripts are not turned on.
+     */
+    pub fn from_config(config: &Config) -> Option<Self> {
+        Some(TranscriptSettings {
+            dir: PathBuf::from(config.transcript_dir.as_ref()?),
+            format: config.transcript_format,
+            max_bytes: config.transcript_max_bytes,
+            retention: config.transcript_retention,
+        })
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Entry {
+    timestamp: u64,
+    room: String,
+    from: String,
+    text: String,
+}
+
+/**
+ * The pool's end of the transcript. Recording only queues the message; a blocking thread writes it
+ * out, so a slow disk costs transcript lines, never broadcast latency.
+ */
+pub struct Transcript {
+    queue: mpsc::Sender<Entry>,
+    dropped: AtomicU64,
+    failed: Arc<AtomicU64>,
+}
+
+impl Transcript {
+    /**
+     * Starts the writer thread. Must be called from within a Tokio runtime.
+     */
+    pub fn start(settings: TranscriptSettings) -> Transcript {
+        let (transcript, entries) = Self::channel(QUEUE_CAPACITY);
+        let failed = transcript.failed.clone();
+        tokio::task::spawn_blocking(move || TranscriptWriter::new(settings).run(entries, &failed));
+        transcript
+    }
+
+    fn channel(capacity: usize) -> (Transcript, mpsc::Receiver<Entry>) {
+        let (queue, entries) = mpsc::channel(capacity);
+        let transcript = Transcript {
+            queue,
+            dropped: AtomicU64::new(0),
+            failed: Arc::new(AtomicU64::new(0)),
+        };
+        (transcript, entries)
+    }
+
+    pub fn record(&self, room: &str, from: &str, text: &str, timestamp: u64) {
+        let entry = Entry {
+            timestamp,
+            room: room.to_string(),
+            from: from.to_string(),
+            text: text.to_string(),
+        };
+        if self.queue.try_send(entry).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /**
+     * Messages left out of the transcript because the writer had fallen behind.
+     */
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /**
+     * Messages left out of the transcript because writing them failed.
+     */
+    pub fn failed(&self) -> u64 {
+        self.failed.load(Ordering::Relaxed)
+    }
+}
+
+/**
+ * Owns the open transcript file. Files are named `transcript-<date>-<n>.<ext>`, so sorting the
+ * names sorts them oldest first; a new file is started on a new (UTC) day or when the current one is full.
+ */
+struct TranscriptWriter {
+    settings: TranscriptSettings,
+    file: Option<BufWriter<File>>,
+    date: String,
+    written: u64,
+}
+
+impl TranscriptWriter {
+    fn new(settings: TranscriptSettings) -> Self {
+        TranscriptWriter {
+            settings,
+            file: None,
+            date: String::new(),
+            written: 0,
+        }
+    }
+
+    fn run(mut self, mut entries: mpsc::Receiver<Entry>, failed: &AtomicU64) {
+        while let Some(entry) = entries.blocking_recv() {
+            if let Err(e) = self.write(&entry) {
+                failed.fetch_add(1, Ordering::Relaxed);
+                eprintln!("Error writing transcript: {}", e);
+            }
+            // Flush once the queue is drained rather than after every line
+            if entries.is_empty() {
+                self.flush();
+            }
+        }
+        self.flush();
+    }
+
+    fn write(&mut self, entry: &Entry) -> io::Result<()> {
+        let line = self.format(entry)?;
+        let date = date_of(entry.timestamp);
+        let full = self.written > 0 && self.written + line.len() as u64 > self.settings.max_bytes;
+        if self.file.is_none() || date != self.date || full {
+            self.rotate(date)?;
+        }
+        if let Some(file) = self.file.as_mut() {
+            file.write_all(line.as_bytes())?;
+            self.written += line.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn format(&self, entry: &Entry) -> io::Result<String> {
+        match self.settings.format {
+            TranscriptFormat::Jsonl => Ok(format!("{}\n", serde_json::to_string(entry)?)),
+            TranscriptFormat::Plain => Ok(format!(
+                "{} {} {} {}: {}\n",
+                date_of(entry.timestamp),
+                time_of_day(entry.timestamp),
+                entry.room,
+                entry.from,
+                entry.text.replace('\r', "\\r").replace('\n', "\\n")
+            )),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self.settings.format {
+            TranscriptFormat::Jsonl => "jsonl",
+            TranscriptFormat::Plain => "log",
+        }
+    }
+
+    fn rotate(&mut self, date: String) -> io::Result<()> {
+        self.flush();
+        self.file = None;
+        fs::create_dir_all(&self.settings.dir)?;
+        // Never reopen an older file, even after a restart: each file is only ever appended to once
+        let path = (0..)
+            .map(|n| {
+                self.settings.dir.join(format!(
+                    "{}{}-{:04}.{}",
+                    FILE_PREFIX,
+                    date,
+                    n,
+                    self.extension()
+                ))
+            })
+            .find(|path| !path.exists())
+            .expect("some index is free");
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(path)?;
+        self.file = Some(BufWriter::new(file));
+        self.date = date;
+        self.written = 0;
+        self.prune()
+    }
+
+    /**
+     * Deletes the oldest transcript files beyond the retention count.
+     */
+    fn prune(&self) -> io::Result<()> {
+        let mut files: Vec<PathBuf> = fs::read_dir(&self.settings.dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                let name = path.file_name().and_then(|name| name.to_str());
+                name.is_some_and(|name| {
+                    name.starts_with(FILE_PREFIX) && name.ends_with(self.extension())
+                })
+            })
+            .collect();
+        files.sort();
+        let excess = files.len().saturating_sub(self.settings.retention.max(1));
+        for path in &files[..excess] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        if let Some(file) = self.file.as_mut() {
+            if let Err(e) = file.flush() {
+                eprintln!("Error flushing transcript: {}", e);
+            }
+        }
+    }
+}
+
+/**
+ * The UTC calendar date (YYYY-MM-DD) of a Unix timestamp.
+ */
+fn date_of(timestamp: u64) -> String {
+    // Howard Hinnant's days-to-civil algorithm
+    let days = (timestamp / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 86_400;
+
+    fn settings(name: &str, format: TranscriptFormat, max_bytes: u64) -> TranscriptSettings {
+        let dir = std::env::temp_dir().join(format!("transcripts-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        TranscriptSettings {
+            dir,
+            format,
+            max_bytes,
+            retention: 10,
+        }
+    }
+
+    fn files_in(settings: &TranscriptSettings) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(&settings.dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn entry(timestamp: u64, text: &str) -> Entry {
+        Entry {
+            timestamp,
+            room: "#lobby".to_string(),
+            from: "anon".to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_date_of_handles_epoch_and_leap_days() {
+        assert_eq!(date_of(0), "1970-01-01");
+        assert_eq!(date_of(951_782_400), "2000-02-29");
+        assert_eq!(date_of(1_700_000_000), "2023-11-14");
+    }
+
+    #[test]
+    fn test_rotates_on_a_new_day_and_when_full() {
+        // Arrange
+        let settings = settings("rotate", TranscriptFormat::Jsonl, 150);
+        let mut writer = TranscriptWriter::new(settings.clone());
+
+        // Act
+        // Each line is roughly 70 bytes, so two fit in a file
+        for (timestamp, text) in [(0, "one"), (1, "two"), (2, "three"), (DAY, "tomorrow")] {
+            writer.write(&entry(timestamp, text)).unwrap();
+        }
+        writer.flush();
+
+        // Assert
+        assert_eq!(
+            files_in(&settings),
+            vec![
+                "transcript-1970-01-01-0000.jsonl",
+                "transcript-1970-01-01-0001.jsonl",
+                "transcript-1970-01-02-0000.jsonl",
+            ]
+        );
+        let first = fs::read_to_string(settings.dir.join("transcript-1970-01-01-0000.jsonl"));
+        assert_eq!(first.unwrap().lines().count(), 2);
+        fs::remove_dir_all(&settings.dir).unwrap();
+    }
+
+    #[test]
+    fn test_keeps_only_the_newest_files() {
+        // Arrange
+        let mut settings = settings("retention", TranscriptFormat::Plain, 1_000);
+        settings.retention = 2;
+        let mut writer = TranscriptWriter::new(settings.clone());
+
+        // Act
+        for day in 0..4 {
+            writer.write(&entry(day * DAY, "hello")).unwrap();
+        }
+        writer.flush();
+
+        // Assert
+        assert_eq!(
+            files_in(&settings),
+            vec![
+                "transcript-1970-01-03-0000.log",
+                "transcript-1970-01-04-0000.log",
+            ]
+        );
+        let newest = fs::read_to_string(settings.dir.join("transcript-1970-01-04-0000.log"));
+        assert_eq!(newest.unwrap(), "1970-01-04 00:00:00 #lobby anon: hello\n");
+        fs::remove_dir_all(&settings.dir).unwrap();
+    }
+
+    #[test]
+    fn test_record_drops_and_counts_when_the_queue_is_full() {
+        // Arrange
+        // Nobody drains this queue, as if the disk had stalled
+        let (transcript, _entries) = Transcript::channel(2);
+
+        // Act
+        for text in ["one", "two", "three", "four"] {
+            transcript.record("#lobby", "anon", text, 0);
+        }
+
+        // Assert
+        assert_eq!(transcript.dropped(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_started_transcript_writes_recorded_messages() {
+        // Arrange
+        let settings = settings("start", TranscriptFormat::Jsonl, 1_000);
+        let transcript = Transcript::start(settings.clone());
+
+        // Act
+        transcript.record("#lobby", "anon", "line one\nline two", 0);
+        // Closing the queue lets the writer finish and flush
+        drop(transcript);
+        let path = settings.dir.join("transcript-1970-01-01-0000.jsonl");
+        let mut contents = String::new();
+        for _ in 0..100 {
+            contents = fs::read_to_string(&path).unwrap_or_default();
+            if !contents.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        // Assert
+        assert_eq!(
+            contents,
+            "{\"timestamp\":0,\"room\":\"#lobby\",\"from\":\"anon\",\"text\":\"line one\\nline two\"}\n"
+        );
+        fs::remove_dir_all(&settings.dir).unwrap();
+    }
+}
@@ -1,44 +1,219 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
+use common::codec::Codec;
 use common::command::Command;
 use common::connection::Connection;
-use std::sync::Arc;
+use common::event::{now, ServerEvent};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    RwLock,
+};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::sync::{mpsc, Mutex};
-
-use crate::user_pool::UserPool;
-
-pub struct User<S>
-where
-    S: AsyncRead + AsyncWrite + Unpin,
-{
-    pub username: String,
-    pub msg_sender: mpsc::Sender<String>,
-    pub msg_receiver: Receiver<String>,
-    pub conn: Connection<S>,
+use tokio::sync::mpsc;
+
+/**
+ * A connected user. The pool writes to `msg_sender`; the user's writer drains the other end of the
+ * channel into their connection, so nothing needs to lock the User to reach the client.
+ */
+pub struct User {
+    username: RwLock<String>,
+    pub msg_sender: mpsc::Sender<ServerEvent>,
+    /** Unix seconds at which the user joined. */
+    pub joined_at: u64,
+    /** Unix seconds at which the user last sent a command. */
+    last_active: AtomicU64,
 }
 
-type Receiver<S> = Arc<Mutex<mpsc::Receiver<S>>>;
+impl User {
+    pub fn new(username: String, msg_sender: mpsc::Sender<ServerEvent>) -> Self {
+        let joined_at = now();
+        User {
+            username: RwLock::new(username),
+            msg_sender,
+            joined_at,
+            last_active: AtomicU64::new(joined_at),
+        }
+    }
+
+    pub fn username(&self) -> String {
+        self.username.read().unwrap().clone()
+    }
+
+    /**
+     * Changes the user's name. Only the pool should call this, while it rekeys its own map.
+     */
+    pub fn set_username(&self, username: String) {
+        *self.username.write().unwrap() = username;
+    }
+
+    /**
+     * Records that the user just did something, resetting their idle time.
+     */
+    pub fn touch(&self) {
+        self.last_active.store(now(), Ordering::Relaxed);
+    }
+
+    /**
+     * Seconds since the user last sent a command.
+     */
+    pub fn idle_secs(&self) -> u64 {
+        now().saturating_sub(self.last_active.load(Ordering::Relaxed))
+    }
 
-impl<S: AsyncRead + AsyncWrite + Unpin> User<S> {
     /**
-     * Handles a command from the User's connection (from the client)
+     * Handles a command from the User's connection (from the client), forwarding it to the pool.
+     * Reads from its own half of the connection so the User is never locked while waiting on the socket.
+     * A message it can't make sense of is answered with an error on `replies` and skipped.
+     * Returns on `leave`, EOF, or a read error; dropping `msg_sender` then tells the pool's
+     * dispatcher the connection is gone, so it deregisters the user.
      */
-    pub async fn handle_commands(&mut self, user_pool: Arc<UserPool<S>>) {
+    pub async fn handle_commands<R: AsyncRead + Unpin, C: Codec>(
+        reader: &mut Connection<R, C>,
+        msg_sender: mpsc::Sender<Command>,
+        replies: mpsc::Sender<ServerEvent>,
+    ) {
         loop {
-            match self.conn.read_command().await.unwrap() {
-                Some(Command::SendMessage(message)) => {
-                    let _send = self.msg_sender.send(format!("send {}", message)).await;
+            let command = match reader.read_command().await {
+                Ok(Some(command)) => command,
+                Ok(None) => break,
+                Err(e) if e.is_bad_message() => {
+                    let reply = ServerEvent::Error(format!("Couldn't read that command: {}", e));
+                    let _ = replies.try_send(reply);
+                    continue;
                 }
-                Some(Command::Leave) => {
-                    let _send = self.msg_sender.send("leave".to_string()).await;
-                    break;
-                }
-                _ => {
+                Err(e) => {
+                    eprintln!("Error reading command: {}", e);
+                    // Say why, in case the client can still hear it (say, after an overlong line)
+                    let reply = ServerEvent::Error(format!("Closing the connection: {}", e));
+                    let _ = replies.try_send(reply);
                     break;
                 }
+            };
+            let leaving = command == Command::Leave;
+            let _send = msg_sender.send(command).await;
+            if leaving {
+                break;
             }
         }
     }
+
+    /**
+     * Drains the messages the pool has queued for this user into their connection (to the client).
+     * Runs until every sender is gone or the client stops accepting writes.
+     */
+    pub async fn write_messages<W: AsyncWrite + Unpin, C: Codec>(
+        mut msg_receiver: mpsc::Receiver<ServerEvent>,
+        mut writer: Connection<W, C>,
+    ) {
+        while let Some(event) = msg_receiver.recv().await {
+            if writer.send_event(event).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::codec::LineCodec;
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_writer_drains_queued_messages_into_connection() {
+        // Arrange
+        let (stream, client) = duplex(64);
+        let (tx, rx) = mpsc::channel(5);
+        let user = User::new("anon".to_string(), tx);
+
+        // Act
+        let writer = tokio::spawn(User::write_messages(
+            rx,
+            Connection::new(stream, LineCodec::default()),
+        ));
+        user.msg_sender
+            .send(ServerEvent::Welcome("anon".to_string()))
+            .await
+            .unwrap();
+        // Dropping the last sender lets the writer finish
+        drop(user);
+        writer.await.unwrap();
+
+        // Assert
+        let mut client_conn = Connection::new(client, LineCodec::default());
+        let received = client_conn.read_event().await.unwrap();
+        assert_eq!(received, Some(ServerEvent::Welcome("anon".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_handle_commands_returns_when_client_hangs_up() {
+        // Arrange
+        let (stream, client) = duplex(64);
+        let mut reader = Connection::new(stream, LineCodec::default());
+        let (tx, mut rx) = mpsc::channel(5);
+        let (replies, _) = mpsc::channel(5);
+
+        // Act
+        drop(client);
+        User::handle_commands(&mut reader, tx, replies).await;
+
+        // Assert
+        assert_eq!(
+            rx.recv().await,
+            None,
+            "the pool should see the channel close"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_commands_answers_and_skips_malformed_input() {
+        // Arrange
+        let (stream, mut client) = duplex(64);
+        let mut reader = Connection::new(stream, LineCodec::text());
+        let (tx, mut rx) = mpsc::channel(5);
+        let (replies, mut errors) = mpsc::channel(5);
+
+        // Act
+        client.write_all(b"sned typo\nsend hi\n").await.unwrap();
+        drop(client);
+        User::handle_commands(&mut reader, tx, replies).await;
+
+        // Assert
+        assert_eq!(
+            errors.recv().await,
+            Some(ServerEvent::Error(
+                "Couldn't read that command: unrecognised message".to_string()
+            ))
+        );
+        assert_eq!(
+            rx.recv().await,
+            Some(Command::SendMessage("hi".to_string()))
+        );
+        assert_eq!(rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_handle_commands_hangs_up_on_an_overlong_line() {
+        // Arrange
+        let (stream, mut client) = duplex(64);
+        let mut reader = Connection::new(stream, LineCodec::text().with_max_length(16));
+        let (tx, mut rx) = mpsc::channel(5);
+        let (replies, mut errors) = mpsc::channel(5);
+
+        // Act
+        // No newline and the client stays connected; the limit alone ends it
+        client
+            .write_all(b"send a line that never ends")
+            .await
+            .unwrap();
+        User::handle_commands(&mut reader, tx, replies).await;
+
+        // Assert
+        assert!(matches!(
+            errors.recv().await,
+            Some(ServerEvent::Error(reason)) if reason.starts_with("Closing the connection")
+        ));
+        assert_eq!(rx.recv().await, None);
+    }
 }
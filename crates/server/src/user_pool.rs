@@ -1,111 +1,466 @@
-use crate::user::User;
-use common::command::Command;
+use crate::{
+    history::History,
+    rooms::Rooms,
+    storage::{MessageStore, StoreQueue},
+    transcript::Transcript,
+    user::User,
+};
+use common::{
+    command::Command,
+    config::{Config, DEFAULT_HISTORY_PAGE_LIMIT, DEFAULT_HISTORY_SIZE},
+    event::{LeaveReason, OnlineUser, ServerEvent},
+    protocol::{self, DEFAULT_ROOM},
+    username::{fold, Username, UsernameRules},
+};
 use log::debug;
 use std::{
     collections::{hash_map::Entry, HashMap},
-    sync::Arc,
-};
-use tokio::{
-    io::{AsyncRead, AsyncWrite},
-    sync::{Mutex, RwLock},
+    sync::{Arc, Mutex},
 };
+use tokio::sync::{mpsc, RwLock};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
 
 /**
- * Manages the Users. Generic over a stream so that TcpStream can be mocked in unit tests.
+ * Manages the Users. Users are reached through their message channels only, so the pool never
+ * touches a socket and a slow client cannot hold up anyone else.
+ * `users` is keyed by each name's folded form (see `username::fold`), so names differing only in
+ * case or Unicode form collide. When several locks are needed they are taken in the order
+ * `users`, `rooms`, `history`.
  */
-pub struct UserPool<S>
-where
-    S: AsyncRead + AsyncWrite + Unpin,
-{
-    users: RwLock<HashMap<String, Arc<Mutex<User<S>>>>>,
+pub struct UserPool {
+    users: RwLock<HashMap<String, Arc<User>>>,
+    rooms: RwLock<Rooms>,
+    history: Mutex<History>,
+    store: Option<StoreQueue>,
+    transcript: Option<Transcript>,
+    history_page_limit: usize,
+    username_rules: UsernameRules,
+    shutdown: CancellationToken,
 }
 
-impl<S> UserPool<S>
-where
-    S: AsyncRead + AsyncWrite + Unpin,
-{
+impl Default for UserPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UserPool {
     pub fn new() -> Self {
+        Self::build(UsernameRules::default(), DEFAULT_HISTORY_SIZE)
+    }
+
+    /**
+     * A pool set up from `config`. Storage is not opened here; see `with_store`.
+     */
+    pub fn from_config(config: &Config) -> Self {
+        let mut pool = Self::build(config.username_rules(), config.history_size);
+        pool.history_page_limit = config.history_page_limit;
+        pool
+    }
+
+    fn build(username_rules: UsernameRules, history_size: usize) -> Self {
         UserPool {
             users: RwLock::new(HashMap::new()),
+            rooms: RwLock::new(Rooms::new()),
+            history: Mutex::new(History::new(history_size)),
+            store: None,
+            transcript: None,
+            history_page_limit: DEFAULT_HISTORY_PAGE_LIMIT,
+            username_rules,
+            shutdown: CancellationToken::new(),
         }
     }
 
     /**
-     * Adds a unique user to the user pool.
+     * Records every room message in `store` too, and answers `history <n>` from it. The store is
+     * used from a thread of its own, so this must be called from within a Tokio runtime.
      */
-    pub async fn add_user(&self, user: Arc<Mutex<User<S>>>) {
-        let mut hashmap = self.users.write().await;
+    pub fn with_store(mut self, store: Arc<dyn MessageStore>) -> Self {
+        self.store = Some(StoreQueue::start(store));
+        self
+    }
 
-        let username = user.lock().await.username.clone();
+    /**
+     * Appends every room message to `transcript` as well.
+     */
+    pub fn with_transcript(mut self, transcript: Transcript) -> Self {
+        self.transcript = Some(transcript);
+        self
+    }
 
-        match hashmap.entry(username) {
-            Entry::Occupied(_) => {
+    pub fn store(&self) -> Option<&StoreQueue> {
+        self.store.as_ref()
+    }
+
+    pub fn transcript(&self) -> Option<&Transcript> {
+        self.transcript.as_ref()
+    }
+
+    pub fn username_rules(&self) -> &UsernameRules {
+        &self.username_rules
+    }
+
+    /**
+     * Adds a user to the user pool if their name is free, welcoming them, replaying the lobby's recent
+     * messages to them and announcing them to the lobby. Returns false, leaving the pool untouched,
+     * if the name is taken or the pool is shutting down.
+     */
+    pub async fn add_user(&self, user: Arc<User>) -> bool {
+        let mut hashmap = self.users.write().await;
+        if self.is_shutting_down() {
+            return false;
+        }
+        let username = user.username();
+
+        match hashmap.entry(fold(&username)) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
                 let _ = user
-                    .lock()
-                    .await
                     .msg_sender
-                    .send("username_taken".to_string())
-                    .await;
-            }
-            Entry::Vacant(entry) => {
+                    .try_send(ServerEvent::Welcome(username.clone()));
+                self.replay_history(&user, DEFAULT_ROOM);
                 entry.insert(user);
+                let mut rooms = self.rooms.write().await;
+                rooms.enter(&username, DEFAULT_ROOM);
+                let joined = ServerEvent::UserJoined(username.clone());
+                notify_room(&hashmap, &rooms, DEFAULT_ROOM, &username, joined);
+                true
             }
         }
     }
 
     /**
-     * Removes a user from the user pool
+     * Tells every user the server is going away and empties the pool, so each user's queue closes
+     * once their connection lets go of them. Connections waiting on `shutting_down` are released.
      */
-    pub async fn remove_user_with_username(&self, username: String) {
+    pub async fn shutdown(&self) {
         let mut hashmap = self.users.write().await;
-        if let Entry::Occupied(entry) = hashmap.entry(username) {
-            entry.remove();
+        // Cancelled under the lock, so no one can be added after the pool has been emptied
+        self.shutdown.cancel();
+        let mut rooms = self.rooms.write().await;
+        for (_, user) in hashmap.drain() {
+            if user.msg_sender.try_send(ServerEvent::ShuttingDown).is_err() {
+                debug!(
+                    "Dropped shutdown notice for {}: queue full or closed",
+                    user.username()
+                );
+            }
+            rooms.remove(&user.username());
         }
     }
 
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.is_cancelled()
+    }
+
     /**
-     * Broadcasts a message to all other users
+     * Completes once `shutdown` has been called.
+     */
+    pub fn shutting_down(&self) -> WaitForCancellationFuture<'_> {
+        self.shutdown.cancelled()
+    }
+
+    /**
+     * Removes a user from the user pool, telling the rest of their room why they left.
+     */
+    pub async fn remove_user_with_username(&self, username: String, reason: LeaveReason) {
+        let mut hashmap = self.users.write().await;
+        if hashmap.remove(&fold(&username)).is_none() {
+            return;
+        }
+        let mut rooms = self.rooms.write().await;
+        if let Some(room) = rooms.remove(&username) {
+            let left = ServerEvent::UserLeft {
+                username: username.clone(),
+                reason,
+            };
+            notify_room(&hashmap, &rooms, &room, &username, left);
+        }
+    }
+
+    /**
+     * Renames a user, moving their entry to the new name in one step so nobody else can take it in
+     * between, and tells their room (themselves included). A taken name is answered with `UsernameTaken`.
+     */
+    pub async fn rename(&self, user: Arc<User>, new_username: &str) {
+        let new_username = match Username::parse(new_username, &self.username_rules) {
+            Ok(username) => username,
+            Err(e) => {
+                let _ = user
+                    .msg_sender
+                    .try_send(ServerEvent::InvalidUsername(e.to_string()));
+                return;
+            }
+        };
+        let mut hashmap = self.users.write().await;
+        let old_username = user.username();
+        let old_key = fold(&old_username);
+        // Changing only the case of your own name is not a collision
+        if new_username.key() != old_key && hashmap.contains_key(&new_username.key()) {
+            let _ = user.msg_sender.try_send(ServerEvent::UsernameTaken);
+            return;
+        }
+        let Some(entry) = hashmap.remove(&old_key) else {
+            return;
+        };
+        entry.set_username(new_username.to_string());
+        hashmap.insert(new_username.key(), entry);
+
+        let mut rooms = self.rooms.write().await;
+        if let Some(room) = rooms.rename(&old_username, new_username.as_str()) {
+            let renamed = ServerEvent::UserRenamed {
+                from: old_username,
+                to: new_username.to_string(),
+            };
+            notify_room(&hashmap, &rooms, &room, "", renamed);
+        }
+    }
+
+    /**
+     * Broadcasts a message to all other users in the sender's room. Queues onto each user's channel
+     * without waiting, so a user whose queue is full misses the message rather than stalling the broadcast.
      */
     pub async fn broadcast(&self, sender_username: String, message: &str) {
         let users = self.users.read().await;
-        for (username, user) in users.iter() {
-            if username.as_str() != sender_username {
-                debug!("SENDING");
-                let _ = user.lock().await.msg_sender.send(message.to_string()).await;
+        let rooms = self.rooms.read().await;
+        let Some(room) = rooms.room_of(&sender_username) else {
+            return;
+        };
+        let event = ServerEvent::message(room, &sender_username, message);
+        if let ServerEvent::Message { timestamp, .. } = &event {
+            if let Some(store) = &self.store {
+                store.record(room, &sender_username, message, *timestamp);
+            }
+            if let Some(transcript) = &self.transcript {
+                transcript.record(room, &sender_username, message, *timestamp);
             }
         }
+        self.history.lock().unwrap().record(room, event.clone());
+        notify_room(&users, &rooms, room, &sender_username, event);
     }
+
+    /**
+     * Queues the room's recent messages for a user who has just arrived in it.
+     */
+    fn replay_history(&self, user: &User, room: &str) {
+        for event in self.history.lock().unwrap().replay(room) {
+            if user.msg_sender.try_send(event).is_err() {
+                debug!(
+                    "Dropped history for {}: queue full or closed",
+                    user.username()
+                );
+                break;
+            }
+        }
+    }
+
     /**
-     * Broadcasts a message to the client telling them to choose another username
+     * Queues an event for one user, returning false if nobody by that name is online.
+     * Like `broadcast`, it never waits on a full queue.
      */
-    pub async fn alert_duplicate_username(&self, user: Arc<Mutex<User<S>>>) {
-        let _send = user
-            .lock()
+    pub async fn send_to(&self, username: &str, event: ServerEvent) -> bool {
+        let users = self.users.read().await;
+        match users.get(&fold(username)) {
+            Some(user) => {
+                if user.msg_sender.try_send(event).is_err() {
+                    debug!("Dropped message for {}: queue full or closed", username);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /**
+     * Sends `text` to `to` alone. The sender is told if `to` isn't a valid name, isn't online, or
+     * there is nothing to send.
+     */
+    async fn send_private_message(&self, user: Arc<User>, to: &str, text: &str) {
+        let error = match Username::parse(to, &self.username_rules) {
+            Err(e) => format!("{:?} is not a valid username: {}", to, e),
+            Ok(_) if text.is_empty() => "A private message needs some text".to_string(),
+            Ok(to) => {
+                let event = ServerEvent::private_message(&user.username(), text);
+                if self.send_to(to.as_str(), event).await {
+                    return;
+                }
+                format!("{} is not online", to)
+            }
+        };
+        let _ = user.msg_sender.try_send(ServerEvent::Error(error));
+    }
+
+    /**
+     * Everyone currently connected, sorted by name, with their join and idle times.
+     */
+    pub async fn online_users(&self) -> Vec<OnlineUser> {
+        let users = self.users.read().await;
+        let mut online: Vec<OnlineUser> = users
+            .values()
+            .map(|user| OnlineUser {
+                username: user.username(),
+                joined_at: user.joined_at,
+                idle_secs: user.idle_secs(),
+            })
+            .collect();
+        online.sort_by(|a, b| a.username.cmp(&b.username));
+        online
+    }
+
+    pub async fn user_count(&self) -> usize {
+        self.users.read().await.len()
+    }
+
+    /**
+     * How many rooms exist, the lobby included even when it is empty.
+     */
+    pub async fn room_count(&self) -> usize {
+        self.rooms.read().await.list().len()
+    }
+
+    /**
+     * Moves a user into `room`, creating it if nobody is there yet, confirms the move to them and
+     * replays what was said there recently.
+     */
+    pub async fn join_room(&self, user: Arc<User>, room: &str) {
+        let Some(room) = protocol::room_name(room) else {
+            let _ = user.msg_sender.try_send(ServerEvent::Error(format!(
+                "{:?} is not a valid room name",
+                room
+            )));
+            return;
+        };
+        self.rooms.write().await.enter(&user.username(), &room);
+        let _ = user
+            .msg_sender
+            .try_send(ServerEvent::RoomJoined(room.clone()));
+        self.replay_history(&user, &room);
+    }
+
+    /**
+     * Sends the user up to `count` of their room's latest messages, after leaving out the newest `skip`,
+     * from storage if there is any and otherwise from the in-memory history.
+     */
+    pub async fn send_history(&self, user: Arc<User>, count: usize, skip: usize) {
+        let count = count.min(self.history_page_limit);
+        // No pool lock is held while the store is read
+        let room = self
+            .rooms
+            .read()
             .await
-            .conn
-            .send_command(Command::UsernameTaken)
-            .await;
+            .room_of(&user.username())
+            .map(str::to_string);
+        let Some(room) = room else {
+            return;
+        };
+        let messages = match &self.store {
+            Some(store) => match store.recent(&room, count, skip).await {
+                Ok(messages) => messages
+                    .into_iter()
+                    .map(|event| ServerEvent::History(Box::new(event)))
+                    .collect(),
+                Err(e) => {
+                    eprintln!("Error reading stored messages: {}", e);
+                    let reply = ServerEvent::Error("Could not read the history".to_string());
+                    let _ = user.msg_sender.try_send(reply);
+                    return;
+                }
+            },
+            None => self.history.lock().unwrap().recent(&room, count, skip),
+        };
+        for event in messages {
+            if user.msg_sender.send(event).await.is_err() {
+                break;
+            }
+        }
     }
 
+    /**
+     * Sends the user the list of open rooms and how many people are in each.
+     */
+    pub async fn list_rooms(&self, user: Arc<User>) {
+        let rooms = self.rooms.read().await.list();
+        let _ = user.msg_sender.try_send(ServerEvent::RoomList(rooms));
+    }
     /**
      * Processes a command from a user.
      */
-    pub async fn process_command(&self, command: Option<Command>, user: Arc<Mutex<User<S>>>) {
+    pub async fn process_command(&self, command: Command, user: Arc<User>) {
         debug!("Handling command");
+        user.touch();
         match command {
-            Some(Command::SendMessage(message)) => {
-                self.broadcast(user.lock().await.username.clone(), &message)
-                    .await;
+            Command::SendMessage(message) => {
+                self.broadcast(user.username(), &message).await;
             }
-            Some(Command::Leave) => {
-                self.remove_user_with_username(user.lock().await.username.clone())
+            Command::Leave => {
+                self.remove_user_with_username(user.username(), LeaveReason::Quit)
                     .await;
             }
-            Some(Command::UsernameTaken) => {
-                self.alert_duplicate_username(user.clone()).await;
+            Command::JoinRoom(room) => {
+                self.join_room(user, &room).await;
+            }
+            Command::LeaveRoom => {
+                self.join_room(user, DEFAULT_ROOM).await;
+            }
+            Command::ListRooms => {
+                self.list_rooms(user).await;
+            }
+            Command::Nick(new_username) => {
+                self.rename(user, &new_username).await;
+            }
+            Command::History { count, skip } => {
+                self.send_history(user, count, skip).await;
+            }
+            Command::ListUsers => {
+                let online = self.online_users().await;
+                let _ = user.msg_sender.try_send(ServerEvent::UserList(online));
+            }
+            Command::PrivateMessage { to, text } => {
+                self.send_private_message(user, &to, &text).await;
             }
-            _ => {
-                println!("Not a valid command :(")
+            Command::Hello { .. } | Command::Join(_) => {
+                let reply = ServerEvent::Error("Already joined".to_string());
+                let _ = user.msg_sender.try_send(reply);
+            }
+        }
+    }
+
+    /**
+     * Routes the commands forwarded by a user's connection task through `process_command`,
+     * until the user leaves or their task goes away. A task that goes away without a `leave`
+     * means the connection dropped, and the user is removed as disconnected.
+     */
+    pub async fn dispatch(&self, mut msg_receiver: mpsc::Receiver<Command>, user: Arc<User>) {
+        while let Some(command) = msg_receiver.recv().await {
+            let leaving = command == Command::Leave;
+            self.process_command(command, user.clone()).await;
+            if leaving {
+                return;
+            }
+        }
+        self.remove_user_with_username(user.username(), LeaveReason::Disconnected)
+            .await;
+    }
+}
+
+/**
+ * Queues `event` for everyone in `room` except `except`, without waiting on full queues.
+ */
+fn notify_room(
+    users: &HashMap<String, Arc<User>>,
+    rooms: &Rooms,
+    room: &str,
+    except: &str,
+    event: ServerEvent,
+) {
+    for username in rooms.members(room) {
+        if username.as_str() == except {
+            continue;
+        }
+        if let Some(user) = users.get(&fold(username)) {
+            debug!("SENDING");
+            if user.msg_sender.try_send(event.clone()).is_err() {
+                debug!("Dropped message for {}: queue full or closed", username);
             }
         }
     }
@@ -115,29 +470,17 @@ mod tests {
     use std::sync::Arc;
 
     use super::*;
-    use common::connection::Connection;
-    use tokio::{
-        io::{duplex, DuplexStream},
-        sync::{mpsc, Mutex},
-    };
+    use tokio::sync::mpsc;
 
     #[tokio::test]
     async fn test_add_user() {
         // Arrange
-        // Create a mock TcpStream like object
-        let (stream1, _stream2) = duplex(64);
-
-        let user_pool = UserPool::<DuplexStream>::new();
-        let (tx, rx) = mpsc::channel(5);
-        let user = User {
-            username: "anon".to_string(),
-            msg_sender: tx,
-            msg_receiver: Arc::new(Mutex::new(rx)),
-            conn: Connection::new(stream1),
-        };
+        let user_pool = UserPool::new();
+        let (tx, _rx) = mpsc::channel(5);
+        let user = User::new("anon".to_string(), tx);
 
         // Act
-        user_pool.add_user(Arc::new(Mutex::new(user))).await;
+        user_pool.add_user(Arc::new(user)).await;
         let users = user_pool.users.read().await;
 
         // Assert
@@ -146,28 +489,15 @@ mod tests {
     #[tokio::test]
     async fn test_add_distinct_user() {
         // Arrange
-        let (stream1, _) = duplex(64);
-        let (stream2, __) = duplex(64);
-
-        let user_pool = UserPool::<DuplexStream>::new();
-        let (tx1, rx1) = mpsc::channel(5);
-        let (tx2, rx2) = mpsc::channel(5);
-        let user1 = User {
-            username: "anon".to_string(),
-            msg_sender: tx1,
-            msg_receiver: Arc::new(Mutex::new(rx1)),
-            conn: Connection::new(stream1),
-        };
-        let user2 = User {
-            username: "anon2".to_string(),
-            msg_sender: tx2,
-            msg_receiver: Arc::new(Mutex::new(rx2)),
-            conn: Connection::new(stream2),
-        };
+        let user_pool = UserPool::new();
+        let (tx1, _rx1) = mpsc::channel(5);
+        let (tx2, _rx2) = mpsc::channel(5);
+        let user1 = User::new("anon".to_string(), tx1);
+        let user2 = User::new("anon2".to_string(), tx2);
 
         // Act
-        user_pool.add_user(Arc::new(Mutex::new(user1))).await;
-        user_pool.add_user(Arc::new(Mutex::new(user2))).await;
+        user_pool.add_user(Arc::new(user1)).await;
+        user_pool.add_user(Arc::new(user2)).await;
         let users = user_pool.users.read().await;
 
         // Assert
@@ -177,52 +507,64 @@ mod tests {
     #[tokio::test]
     async fn test_add_same_username() {
         // Arrange
-        let (stream1, _) = duplex(64);
-        let (stream2, __) = duplex(64);
-
-        let user_pool = UserPool::<DuplexStream>::new();
-        let (tx1, rx1) = mpsc::channel(5);
-        let (tx2, rx2) = mpsc::channel(5);
-        let user1 = User {
-            username: "anon".to_string(),
-            msg_sender: tx1,
-            msg_receiver: Arc::new(Mutex::new(rx1)),
-            conn: Connection::new(stream1),
-        };
-        let user2 = User {
-            username: "anon".to_string(),
-            msg_sender: tx2,
-            msg_receiver: Arc::new(Mutex::new(rx2)),
-            conn: Connection::new(stream2),
-        };
+        let user_pool = UserPool::new();
+        let (tx1, _rx1) = mpsc::channel(5);
+        let (tx2, mut rx2) = mpsc::channel(5);
+        let user1 = User::new("anon".to_string(), tx1);
+        let user2 = User::new("anon".to_string(), tx2);
 
         // Act
-        user_pool.add_user(Arc::new(Mutex::new(user1))).await;
-        user_pool.add_user(Arc::new(Mutex::new(user2))).await;
+        let first_added = user_pool.add_user(Arc::new(user1)).await;
+        let second_added = user_pool.add_user(Arc::new(user2)).await;
         let users = user_pool.users.read().await;
 
         // Assert
+        assert!(first_added);
+        assert!(!second_added);
         assert!(users.contains_key("anon"));
         assert_eq!(users.len(), 1);
+        // The connection, not the pool, tells the client; nothing is queued for the rejected user
+        assert!(rx2.try_recv().is_err());
+    }
+    #[tokio::test]
+    async fn test_shutdown_tells_everyone_and_empties_the_pool() {
+        // Arrange
+        let user_pool = UserPool::new();
+        let (tx1, mut rx1) = mpsc::channel(5);
+        let user1 = User::new("anon".to_string(), tx1);
+        user_pool.add_user(Arc::new(user1)).await;
+        let (tx2, _rx2) = mpsc::channel(5);
+        let late = User::new("late".to_string(), tx2);
+
+        // Act
+        user_pool.shutdown().await;
+        let late_added = user_pool.add_user(Arc::new(late)).await;
+
+        // Assert
+        assert_eq!(
+            rx1.recv().await,
+            Some(ServerEvent::Welcome("anon".to_string()))
+        );
+        assert_eq!(rx1.recv().await, Some(ServerEvent::ShuttingDown));
+        // The pool let go of the only sender, so the queue is closed
+        assert_eq!(rx1.recv().await, None);
+        assert!(!late_added);
+        assert!(user_pool.users.read().await.is_empty());
+        assert!(user_pool.rooms.read().await.room_of("anon").is_none());
     }
     #[tokio::test]
     async fn test_drop_user() {
         // Arrange
-        let (stream1, _) = duplex(64);
-
-        let user_pool = UserPool::<DuplexStream>::new();
-        let (tx1, rx1) = mpsc::channel(5);
-        let user1 = User {
-            username: "anon".to_string(),
-            msg_sender: tx1,
-            msg_receiver: Arc::new(Mutex::new(rx1)),
-            conn: Connection::new(stream1),
-        };
+        let user_pool = UserPool::new();
+        let (tx1, _rx1) = mpsc::channel(5);
+        let user1 = User::new("anon".to_string(), tx1);
 
         // Act
-        let username = user1.username.clone();
-        user_pool.add_user(Arc::new(Mutex::new(user1))).await;
-        user_pool.remove_user_with_username(username).await;
+        let username = user1.username();
+        user_pool.add_user(Arc::new(user1)).await;
+        user_pool
+            .remove_user_with_username(username, LeaveReason::Quit)
+            .await;
         let users = user_pool.users.read().await;
 
         // Assert
@@ -231,80 +573,504 @@ mod tests {
     #[tokio::test]
     async fn test_user_sends_message() {
         // Arrange
-        let (stream1, _) = duplex(64);
-        let (stream2, __) = duplex(64);
-
-        let user_pool = UserPool::<DuplexStream>::new();
-        let (tx1, rx1) = mpsc::channel(5);
-        let (tx2, rx2) = mpsc::channel(5);
-        let rx2_by_ref = Arc::new(Mutex::new(rx2));
-
-        let user1 = User {
-            username: "anon".to_string(),
-            msg_sender: tx1.clone(),
-            msg_receiver: Arc::new(Mutex::new(rx1)),
-            conn: Connection::new(stream1),
-        };
-        let user2 = User {
-            username: "anon2".to_string(),
-            msg_sender: tx2,
-            msg_receiver: rx2_by_ref.clone(),
-            conn: Connection::new(stream2),
-        };
+        let user_pool = UserPool::new();
+        let (tx1, _rx1) = mpsc::channel(5);
+        let (tx2, mut rx2) = mpsc::channel(5);
+
+        let user1 = User::new("anon".to_string(), tx1.clone());
+        let user2 = User::new("anon2".to_string(), tx2);
 
         // Act
-        let user1_name = user1.username.clone();
-        user_pool.add_user(Arc::new(Mutex::new(user1))).await;
-        user_pool.add_user(Arc::new(Mutex::new(user2))).await;
+        let user1_name = user1.username();
+        user_pool.add_user(Arc::new(user1)).await;
+        user_pool.add_user(Arc::new(user2)).await;
 
         user_pool.broadcast(user1_name, "Hello world!").await;
 
         // Assert
-
-        let rx2_ref_temp = rx2_by_ref.clone();
-        let mut rx2_ref = rx2_ref_temp.lock().await;
-        assert_eq!(rx2_ref.recv().await, Some("Hello world!".to_string()));
+        assert_eq!(
+            rx2.recv().await,
+            Some(ServerEvent::Welcome("anon2".to_string()))
+        );
+        assert!(matches!(
+            rx2.recv().await,
+            Some(ServerEvent::Message { from, text, .. }) if from == "anon" && text == "Hello world!"
+        ));
     }
     #[tokio::test]
     async fn test_user_does_not_receive_own_sent_message() {
         // Arrange
-        let (stream1, _) = duplex(64);
-        let (stream2, __) = duplex(64);
-
-        let user_pool = UserPool::<DuplexStream>::new();
-        let (tx1, rx1) = mpsc::channel(5);
-        let (tx2, rx2) = mpsc::channel(5);
-        let rx1_by_ref = Arc::new(Mutex::new(rx1));
-        let rx2_by_ref = Arc::new(Mutex::new(rx2));
-
-        let user1 = User {
-            username: "anon".to_string(),
-            msg_sender: tx1.clone(),
-            msg_receiver: rx1_by_ref.clone(),
-            conn: Connection::new(stream1),
+        let user_pool = UserPool::new();
+        let (tx1, mut rx1) = mpsc::channel(5);
+        let (tx2, _rx2) = mpsc::channel(5);
+
+        let user1 = User::new("anon".to_string(), tx1.clone());
+        let user2 = User::new("anon2".to_string(), tx2);
+
+        // Act
+        let user1_name = user1.username();
+        user_pool.add_user(Arc::new(user1)).await;
+        user_pool.add_user(Arc::new(user2)).await;
+
+        user_pool.broadcast(user1_name, "Hello world!").await;
+
+        // Assert
+        assert_eq!(
+            rx1.recv().await,
+            Some(ServerEvent::Welcome("anon".to_string()))
+        );
+        assert_eq!(
+            rx1.recv().await,
+            Some(ServerEvent::UserJoined("anon2".to_string()))
+        );
+        // We are only testing that a None value is taken from the receiver channel
+        assert!(
+            rx1.try_recv().is_err(),
+            "User 1 should not receive their own message"
+        );
+    }
+    #[tokio::test]
+    async fn test_broadcast_does_not_wait_on_full_queue() {
+        // Arrange
+        let user_pool = UserPool::new();
+        let (tx1, _rx1) = mpsc::channel(5);
+        let (tx2, mut rx2) = mpsc::channel(2);
+
+        let user1 = User::new("anon".to_string(), tx1);
+        let user2 = User::new("anon2".to_string(), tx2);
+        user_pool.add_user(Arc::new(user1)).await;
+        user_pool.add_user(Arc::new(user2)).await;
+
+        // Act
+        // Nobody drains user 2's queue, so after the welcome and first message it is full
+        user_pool.broadcast("anon".to_string(), "first").await;
+        user_pool.broadcast("anon".to_string(), "second").await;
+
+        // Assert
+        assert_eq!(
+            rx2.recv().await,
+            Some(ServerEvent::Welcome("anon2".to_string()))
+        );
+        assert!(matches!(
+            rx2.recv().await,
+            Some(ServerEvent::Message { text, .. }) if text == "first"
+        ));
+        assert!(rx2.try_recv().is_err());
+    }
+    #[tokio::test]
+    async fn test_dispatch_broadcasts_sent_messages() {
+        // Arrange
+        let user_pool = UserPool::new();
+        let (tx1, _rx1) = mpsc::channel(5);
+        let (tx2, mut rx2) = mpsc::channel(5);
+        let (tx_to_pool, rx_pool) = mpsc::channel(5);
+
+        let user1 = Arc::new(User::new("anon".to_string(), tx1));
+        let user2 = User::new("anon2".to_string(), tx2);
+        user_pool.add_user(user1.clone()).await;
+        user_pool.add_user(Arc::new(user2)).await;
+
+        // Act
+        tx_to_pool
+            .send(Command::SendMessage("Hi there".to_string()))
+            .await
+            .unwrap();
+        tx_to_pool.send(Command::Leave).await.unwrap();
+        user_pool.dispatch(rx_pool, user1).await;
+
+        // Assert
+        assert_eq!(
+            rx2.recv().await,
+            Some(ServerEvent::Welcome("anon2".to_string()))
+        );
+        assert!(matches!(
+            rx2.recv().await,
+            Some(ServerEvent::Message { from, text, .. }) if from == "anon" && text == "Hi there"
+        ));
+        assert!(!user_pool.users.read().await.contains_key("anon"));
+    }
+    #[tokio::test]
+    async fn test_broadcast_stays_within_room() {
+        // Arrange
+        let user_pool = UserPool::new();
+        let (tx1, mut rx1) = mpsc::channel(5);
+        let (tx2, mut rx2) = mpsc::channel(5);
+        let (tx3, mut rx3) = mpsc::channel(5);
+
+        let user1 = Arc::new(User::new("anon".to_string(), tx1));
+        let user2 = Arc::new(User::new("anon2".to_string(), tx2));
+        let user3 = User::new("anon3".to_string(), tx3);
+        user_pool.add_user(user1.clone()).await;
+        user_pool.add_user(user2.clone()).await;
+        user_pool.add_user(Arc::new(user3)).await;
+
+        // Act
+        user_pool
+            .process_command(Command::JoinRoom("rust".to_string()), user1)
+            .await;
+        user_pool
+            .process_command(Command::JoinRoom("#rust".to_string()), user2)
+            .await;
+        user_pool
+            .broadcast("anon".to_string(), "Hi rustaceans")
+            .await;
+
+        // Assert
+        assert_eq!(
+            rx1.recv().await,
+            Some(ServerEvent::Welcome("anon".to_string()))
+        );
+        // Then anon2 and anon3 joining the lobby
+        rx1.recv().await;
+        rx1.recv().await;
+        assert_eq!(
+            rx1.recv().await,
+            Some(ServerEvent::RoomJoined("#rust".to_string()))
+        );
+        // Welcome, anon3 joining the lobby, then the move to #rust
+        for _ in 0..3 {
+            rx2.recv().await;
+        }
+        assert!(matches!(
+            rx2.recv().await,
+            Some(ServerEvent::Message { room, from, text, .. })
+                if room == "#rust" && from == "anon" && text == "Hi rustaceans"
+        ));
+        rx3.recv().await;
+        assert!(
+            rx3.try_recv().is_err(),
+            "User 3 is still in the lobby and should not hear #rust"
+        );
+    }
+    #[tokio::test]
+    async fn test_part_returns_to_lobby_and_rooms_are_listed() {
+        // Arrange
+        let user_pool = UserPool::new();
+        let (tx1, mut rx1) = mpsc::channel(5);
+        let user1 = Arc::new(User::new("anon".to_string(), tx1));
+        user_pool.add_user(user1.clone()).await;
+        user_pool
+            .process_command(Command::JoinRoom("#rust".to_string()), user1.clone())
+            .await;
+
+        // Act
+        user_pool
+            .process_command(Command::LeaveRoom, user1.clone())
+            .await;
+        user_pool.process_command(Command::ListRooms, user1).await;
+
+        // Assert
+        rx1.recv().await;
+        rx1.recv().await;
+        assert_eq!(
+            rx1.recv().await,
+            Some(ServerEvent::RoomJoined(DEFAULT_ROOM.to_string()))
+        );
+        assert_eq!(
+            rx1.recv().await,
+            Some(ServerEvent::RoomList(vec![(DEFAULT_ROOM.to_string(), 1)]))
+        );
+    }
+    #[tokio::test]
+    async fn test_join_invalid_room_is_an_error() {
+        // Arrange
+        let user_pool = UserPool::new();
+        let (tx1, mut rx1) = mpsc::channel(5);
+        let user1 = Arc::new(User::new("anon".to_string(), tx1));
+        user_pool.add_user(user1.clone()).await;
+
+        // Act
+        user_pool.join_room(user1, "two words").await;
+
+        // Assert
+        rx1.recv().await;
+        assert!(matches!(rx1.recv().await, Some(ServerEvent::Error(_))));
+        assert_eq!(
+            user_pool.rooms.read().await.room_of("anon"),
+            Some(DEFAULT_ROOM)
+        );
+    }
+    #[tokio::test]
+    async fn test_private_message_reaches_only_its_target() {
+        // Arrange
+        let user_pool = UserPool::new();
+        let (tx1, mut rx1) = mpsc::channel(5);
+        let (tx2, mut rx2) = mpsc::channel(5);
+        let (tx3, mut rx3) = mpsc::channel(5);
+        let user1 = Arc::new(User::new("anon".to_string(), tx1));
+        for (username, tx) in [("anon2", tx2), ("anon3", tx3)] {
+            let user = User::new(username.to_string(), tx);
+            user_pool.add_user(Arc::new(user)).await;
+        }
+        user_pool.add_user(user1.clone()).await;
+
+        // Act
+        let command = Command::PrivateMessage {
+            to: "anon2".to_string(),
+            text: "just between us".to_string(),
+        };
+        user_pool.process_command(command, user1).await;
+
+        // Assert
+        // Welcome, then anon3 and anon joining the lobby
+        for _ in 0..3 {
+            rx2.recv().await;
+        }
+        assert!(matches!(
+            rx2.recv().await,
+            Some(ServerEvent::PrivateMessage { from, text, .. })
+                if from == "anon" && text == "just between us"
+        ));
+        rx3.recv().await;
+        rx3.recv().await;
+        assert!(rx3.try_recv().is_err());
+        rx1.recv().await;
+        assert!(rx1.try_recv().is_err());
+    }
+    #[tokio::test]
+    async fn test_private_message_to_offline_user_is_an_error() {
+        // Arrange
+        let user_pool = UserPool::new();
+        let (tx1, mut rx1) = mpsc::channel(5);
+        let user1 = Arc::new(User::new("anon".to_string(), tx1));
+        user_pool.add_user(user1.clone()).await;
+
+        // Act
+        let command = Command::PrivateMessage {
+            to: "ghost".to_string(),
+            text: "hello?".to_string(),
         };
-        let user2 = User {
-            username: "anon2".to_string(),
-            msg_sender: tx2,
-            msg_receiver: rx2_by_ref.clone(),
-            conn: Connection::new(stream2),
+        user_pool.process_command(command, user1).await;
+
+        // Assert
+        rx1.recv().await;
+        assert_eq!(
+            rx1.recv().await,
+            Some(ServerEvent::Error("ghost is not online".to_string()))
+        );
+    }
+    #[tokio::test]
+    async fn test_private_message_to_invalid_name_is_an_error() {
+        // Arrange
+        let user_pool = UserPool::new();
+        let (tx1, mut rx1) = mpsc::channel(5);
+        let (tx2, mut rx2) = mpsc::channel(5);
+        let user1 = Arc::new(User::new("anon".to_string(), tx1));
+        let user2 = User::new("bob".to_string(), tx2);
+        user_pool.add_user(user1.clone()).await;
+        user_pool.add_user(Arc::new(user2)).await;
+
+        // Act
+        let misaddressed = Command::PrivateMessage {
+            to: "bob extra".to_string(),
+            text: "hi".to_string(),
+        };
+        user_pool.process_command(misaddressed, user1.clone()).await;
+        let empty = Command::PrivateMessage {
+            to: "bob".to_string(),
+            text: String::new(),
+        };
+        user_pool.process_command(empty, user1).await;
+
+        // Assert
+        rx1.recv().await;
+        rx1.recv().await;
+        assert!(matches!(
+            rx1.recv().await,
+            Some(ServerEvent::Error(reason)) if reason.starts_with("\"bob extra\" is not a valid username")
+        ));
+        assert_eq!(
+            rx1.recv().await,
+            Some(ServerEvent::Error(
+                "A private message needs some text".to_string()
+            ))
+        );
+        rx2.recv().await;
+        assert!(rx2.try_recv().is_err(), "bob should get nothing");
+    }
+    #[tokio::test]
+    async fn test_who_lists_everyone_online() {
+        // Arrange
+        let user_pool = UserPool::new();
+        let (tx1, mut rx1) = mpsc::channel(5);
+        let (tx2, _rx2) = mpsc::channel(5);
+        let user1 = Arc::new(User::new("anon".to_string(), tx1));
+        let user2 = User::new("anon2".to_string(), tx2);
+        user_pool.add_user(user1.clone()).await;
+        user_pool.add_user(Arc::new(user2)).await;
+
+        // Act
+        user_pool
+            .process_command(Command::ListUsers, user1.clone())
+            .await;
+
+        // Assert
+        rx1.recv().await;
+        rx1.recv().await;
+        let Some(ServerEvent::UserList(online)) = rx1.recv().await else {
+            panic!("expected a user list");
         };
+        let names: Vec<&str> = online.iter().map(|u| u.username.as_str()).collect();
+        assert_eq!(names, vec!["anon", "anon2"]);
+        assert_eq!(online[0].joined_at, user1.joined_at);
+        assert!(online[0].idle_secs <= 1);
+    }
+    #[tokio::test]
+    async fn test_nick_rekeys_user_and_tells_the_room() {
+        // Arrange
+        let user_pool = UserPool::new();
+        let (tx1, mut rx1) = mpsc::channel(5);
+        let (tx2, mut rx2) = mpsc::channel(5);
+        let user1 = Arc::new(User::new("anon".to_string(), tx1));
+        let user2 = User::new("anon2".to_string(), tx2);
+        user_pool.add_user(Arc::new(user2)).await;
+        user_pool.add_user(user1.clone()).await;
 
         // Act
-        let user1_name = user1.username.clone();
-        user_pool.add_user(Arc::new(Mutex::new(user1))).await;
-        user_pool.add_user(Arc::new(Mutex::new(user2))).await;
+        user_pool
+            .process_command(Command::Nick("davey".to_string()), user1.clone())
+            .await;
 
-        user_pool.broadcast(user1_name, "Hello world!").await;
+        // Assert
+        let renamed = Some(ServerEvent::UserRenamed {
+            from: "anon".to_string(),
+            to: "davey".to_string(),
+        });
+        rx1.recv().await;
+        assert_eq!(rx1.recv().await, renamed);
+        rx2.recv().await;
+        rx2.recv().await;
+        assert_eq!(rx2.recv().await, renamed);
+        assert_eq!(user1.username(), "davey");
+        let users = user_pool.users.read().await;
+        assert!(users.contains_key("davey"));
+        assert!(!users.contains_key("anon"));
+        assert_eq!(
+            user_pool.rooms.read().await.room_of("davey"),
+            Some(DEFAULT_ROOM)
+        );
+    }
+    #[tokio::test]
+    async fn test_nick_rejects_taken_name() {
+        // Arrange
+        let user_pool = UserPool::new();
+        let (tx1, mut rx1) = mpsc::channel(5);
+        let (tx2, _rx2) = mpsc::channel(5);
+        let user1 = Arc::new(User::new("anon".to_string(), tx1));
+        let user2 = User::new("anon2".to_string(), tx2);
+        user_pool.add_user(user1.clone()).await;
+        user_pool.add_user(Arc::new(user2)).await;
+
+        // Act
+        user_pool.rename(user1.clone(), "anon2").await;
+
+        // Assert
+        rx1.recv().await;
+        rx1.recv().await;
+        assert_eq!(rx1.recv().await, Some(ServerEvent::UsernameTaken));
+        assert_eq!(user1.username(), "anon");
+        assert_eq!(user_pool.users.read().await.len(), 2);
+    }
+    #[tokio::test]
+    async fn test_names_differing_only_in_case_collide() {
+        // Arrange
+        let user_pool = UserPool::new();
+        let (tx1, _rx1) = mpsc::channel(5);
+        let (tx2, _rx2) = mpsc::channel(5);
+        let user1 = User::new("Davey".to_string(), tx1);
+        let user2 = User::new("DAVEY".to_string(), tx2);
+
+        // Act
+        let first_added = user_pool.add_user(Arc::new(user1)).await;
+        let second_added = user_pool.add_user(Arc::new(user2)).await;
+
+        // Assert
+        assert!(first_added);
+        assert!(!second_added);
+        assert!(user_pool.send_to("dAvEy", ServerEvent::UsernameTaken).await);
+    }
+    #[tokio::test]
+    async fn test_nick_rejects_invalid_name_but_allows_recasing() {
+        // Arrange
+        let user_pool = UserPool::new();
+        let (tx1, mut rx1) = mpsc::channel(5);
+        let user1 = Arc::new(User::new("davey".to_string(), tx1));
+        user_pool.add_user(user1.clone()).await;
+
+        // Act
+        user_pool.rename(user1.clone(), "two words").await;
+        user_pool.rename(user1.clone(), "Davey").await;
 
         // Assert
+        rx1.recv().await;
+        assert!(matches!(
+            rx1.recv().await,
+            Some(ServerEvent::InvalidUsername(_))
+        ));
+        assert!(matches!(
+            rx1.recv().await,
+            Some(ServerEvent::UserRenamed { to, .. }) if to == "Davey"
+        ));
+        assert_eq!(user1.username(), "Davey");
+    }
+    #[tokio::test]
+    async fn test_newcomers_get_recent_messages_as_history() {
+        // Arrange
+        let user_pool = UserPool::build(UsernameRules::default(), 2);
+        let (tx1, _rx1) = mpsc::channel(5);
+        let (tx2, mut rx2) = mpsc::channel(5);
+        let user1 = User::new("anon".to_string(), tx1);
+        user_pool.add_user(Arc::new(user1)).await;
+        for text in ["one", "two", "three"] {
+            user_pool.broadcast("anon".to_string(), text).await;
+        }
 
-        let rx1_ref_temp = rx1_by_ref.clone();
-        let mut rx1_ref = rx1_ref_temp.lock().await;
-        // We are only testing that a None value is taken from the receiver channel, so can panic otherwise
-        match rx1_ref.try_recv().ok() {
-            Some(_) => panic!("User 1 should not receive their own message"),
-            None => assert!(true),
+        // Act
+        let user2 = User::new("anon2".to_string(), tx2);
+        user_pool.add_user(Arc::new(user2)).await;
+
+        // Assert
+        assert_eq!(
+            rx2.recv().await,
+            Some(ServerEvent::Welcome("anon2".to_string()))
+        );
+        for expected in ["two", "three"] {
+            assert!(matches!(
+                rx2.recv().await,
+                Some(ServerEvent::History(event))
+                    if matches!(event.as_ref(), ServerEvent::Message { text, .. } if text == expected)
+            ));
+        }
+        assert!(rx2.try_recv().is_err());
+    }
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn test_history_command_pages_back_through_the_store() {
+        // Arrange
+        let store = Arc::new(crate::storage::SqliteStore::open_in_memory().unwrap());
+        // The in-memory history holds a single message, so older ones can only come from the store
+        let user_pool = UserPool::build(UsernameRules::default(), 1).with_store(store.clone());
+        let (tx1, mut rx1) = mpsc::channel(5);
+        let user1 = Arc::new(User::new("anon".to_string(), tx1));
+        user_pool.add_user(user1.clone()).await;
+        for text in ["one", "two", "three"] {
+            user_pool.broadcast("anon".to_string(), text).await;
+        }
+
+        // Act
+        let page = |skip| Command::History { count: 2, skip };
+        user_pool.process_command(page(0), user1.clone()).await;
+        user_pool.process_command(page(2), user1).await;
+
+        // Assert
+        rx1.recv().await;
+        for expected in ["two", "three", "one"] {
+            assert!(matches!(
+                rx1.recv().await,
+                Some(ServerEvent::History(event))
+                    if matches!(event.as_ref(), ServerEvent::Message { text, .. } if text == expected)
+            ));
         }
+        assert_eq!(store.recent(DEFAULT_ROOM, 10, 0).unwrap().len(), 3);
     }
 }